@@ -0,0 +1,85 @@
+use actix_web::{delete, get, post};
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web::web::{Json, Path};
+use http::StatusCode;
+use omnipaxos_core::util::NodeId;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::config::NodeConfig;
+use crate::forward;
+use crate::kv_controller::{bad_request, deadline, storage_error};
+use crate::membership;
+use crate::CLUSTER;
+
+/// Adds a node to the cluster. The node's process is started with `--join` to fetch the state
+/// of the new configuration once it is decided.
+#[post("/admin/nodes")]
+pub async fn add_node(req: HttpRequest, node: Json<NodeConfig>) -> HttpResponse {
+    let mut cluster = CLUSTER.read().unwrap().clone();
+    cluster.nodes.push(node.clone());
+    if let Err(error) = cluster.validate() {
+        return bad_request(error);
+    }
+    change_membership(&req, Some(&*node), cluster.nodes).await
+}
+
+/// Removes a node from the cluster. Its process keeps running but no longer serves requests.
+#[delete("/admin/nodes/{pid}")]
+pub async fn remove_node(req: HttpRequest, pid: Path<NodeId>) -> HttpResponse {
+    let pid = pid.into_inner();
+    let mut nodes = CLUSTER.read().unwrap().nodes.clone();
+    if !nodes.iter().any(|node| node.pid == pid) {
+        return HttpResponse::NotFound()
+            .content_type("application/json")
+            .status(StatusCode::NOT_FOUND)
+            .json(format!("Node {} is not in the cluster", pid));
+    }
+    nodes.retain(|node| node.pid != pid);
+    if nodes.is_empty() {
+        return bad_request(String::from("The last node can not be removed"));
+    }
+    change_membership::<()>(&req, None, nodes).await
+}
+
+/// The state the current configuration started from, for nodes joining it.
+#[get("/admin/configuration")]
+pub async fn configuration() -> HttpResponse {
+    match membership::started() {
+        Some(state) => HttpResponse::Ok()
+            .content_type("application/json")
+            .status(StatusCode::OK)
+            .json(state),
+        None => HttpResponse::NotFound()
+            .content_type("application/json")
+            .status(StatusCode::NOT_FOUND)
+            .json("The cluster still runs its initial configuration"),
+    }
+}
+
+async fn change_membership<B: Serialize>(req: &HttpRequest, body: Option<&B>, nodes: Vec<NodeConfig>) -> HttpResponse {
+    let deadline = match deadline(req) {
+        Ok(deadline) => deadline,
+        Err(error) => return bad_request(error),
+    };
+    if let Some(response) = forward::to_leader(req, body, deadline).await {
+        return response;
+    }
+    let pids: Vec<NodeId> = nodes.iter().map(|node| node.pid).collect();
+    let configuration_id = match membership::reconfigure(nodes, deadline).await {
+        Ok(configuration_id) => configuration_id,
+        Err(error) => return storage_error(error),
+    };
+    info!(configuration_id, nodes = ?pids, "membership changed");
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .status(StatusCode::OK)
+        .json(MembershipResponse { configuration_id, nodes: pids })
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MembershipResponse {
+    pub configuration_id: u32,
+    pub nodes: Vec<NodeId>,
+}
@@ -0,0 +1,379 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use omnipaxos_core::util::NodeId;
+use serde::{Deserialize, Serialize};
+
+use crate::{LOCAL_HTTP_ADDRESS, PEER_PORT_BASE, PERSIST_PATH, SERVERS};
+
+/// The layout of a cluster, read from a TOML or JSON file by every node started with `--node-id`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ClusterConfig {
+    #[serde(default = "default_configuration_id")]
+    pub configuration_id: u32,
+    /// What a node does with requests that only the leader can serve.
+    #[serde(default)]
+    pub forwarding: Forwarding,
+    #[serde(default)]
+    pub handover: HandoverConfig,
+    #[serde(default)]
+    pub compaction: CompactionConfig,
+    #[serde(default)]
+    pub state_machine: StateMachine,
+    /// `error`, `warn`, `info`, `debug` or `trace`, or a filter such as `web_server::storage=debug`.
+    pub log_level: Option<String>,
+    pub nodes: Vec<NodeConfig>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Forwarding {
+    /// Sends the request on to the leader and relays its response.
+    #[default]
+    Proxy,
+    /// Answers 307 with the leader's address in `Location` and `Leader-Location`.
+    Redirect,
+}
+
+/// Where each node keeps the state machine it applies the log into.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StateMachine {
+    /// In memory, rebuilt from the log and snapshots after a restart.
+    #[default]
+    Memory,
+    /// In memory and in a sled tree under the node's storage directory, written together with
+    /// the applied index so a restart only replays the log after it.
+    Sled,
+}
+
+/// When a node with more client requests than the leader takes over leadership.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct HandoverConfig {
+    pub enabled: bool,
+    /// How many more requests than the leader a node must have handled within the window to
+    /// take over.
+    pub threshold: u64,
+    /// How long after a leader change no node tries to take over, so leadership does not flap.
+    pub cooldown_ms: u64,
+    /// How far back the nodes' request counts are compared.
+    pub window_ms: u64,
+}
+
+impl Default for HandoverConfig {
+    fn default() -> Self {
+        HandoverConfig {
+            enabled: true,
+            threshold: 100,
+            cooldown_ms: 10_000,
+            window_ms: 30_000,
+        }
+    }
+}
+
+/// When the leader compacts the decided prefix of the log into a snapshot.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct CompactionConfig {
+    pub enabled: bool,
+    /// Compact once this many entries were decided since the last compaction.
+    pub every_entries: u64,
+    /// Compact once the entries decided since the last compaction take this many bytes.
+    pub max_bytes: u64,
+    /// Store deltas of the changed keys in each node's snapshot chain and trim the log,
+    /// instead of snapshotting the whole state into the log.
+    pub delta_snapshots: bool,
+    /// Fold the snapshot chain into a full snapshot after this many deltas.
+    pub consolidate_every: u64,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        CompactionConfig {
+            enabled: true,
+            every_entries: 1000,
+            max_bytes: 1024 * 1024,
+            delta_snapshots: false,
+            consolidate_every: 10,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NodeConfig {
+    pub pid: NodeId,
+    /// Where the peers reach the node with OmniPaxos messages, a `host:port` resolved whenever
+    /// they connect.
+    pub peer_address: String,
+    /// Where clients and the other nodes reach the node's REST API, a `host:port`.
+    pub http_address: String,
+    /// The local address the node listens for its peers on, all interfaces on the port of
+    /// `peer_address` if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_bind: Option<String>,
+    /// The local address the node serves the REST API on, all interfaces on the port of
+    /// `http_address` if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_bind: Option<String>,
+    pub storage_dir: String,
+}
+
+impl NodeConfig {
+    pub fn peer_bind_address(&self) -> String {
+        bind_address(self.peer_bind.as_ref(), &self.peer_address)
+    }
+
+    pub fn http_bind_address(&self) -> String {
+        bind_address(self.http_bind.as_ref(), &self.http_address)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let addresses = [&self.peer_address, &self.http_address];
+        for address in addresses.into_iter().chain(&self.peer_bind).chain(&self.http_bind) {
+            if port(address).is_none() {
+                return Err(format!("Node {} has the invalid address {}, expected host:port", self.pid, address));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn bind_address(bind: Option<&String>, advertised: &str) -> String {
+    match bind {
+        Some(bind) => bind.clone(),
+        None => format!("0.0.0.0:{}", port(advertised).unwrap_or_default()),
+    }
+}
+
+/// The port of a `host:port` address with a non-empty host.
+fn port(address: &str) -> Option<u16> {
+    let (host, port) = address.rsplit_once(':')?;
+    if host.is_empty() {
+        return None;
+    }
+    port.parse().ok()
+}
+
+fn default_configuration_id() -> u32 {
+    1
+}
+
+impl ClusterConfig {
+    /// Reads the file as TOML if its extension is `.toml` and as JSON otherwise.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read cluster file {}: {}", path.display(), e))?;
+        let config: ClusterConfig = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(|e| e.to_string()),
+            _ => serde_json::from_str(&contents).map_err(|e| e.to_string()),
+        }
+        .map_err(|e| format!("Invalid cluster file {}: {}", path.display(), e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The cluster of `SERVERS` run inside a single process, all on localhost and sharing
+    /// one REST API.
+    pub fn local() -> Self {
+        ClusterConfig {
+            configuration_id: default_configuration_id(),
+            forwarding: Forwarding::default(),
+            handover: HandoverConfig::default(),
+            compaction: CompactionConfig::default(),
+            state_machine: StateMachine::default(),
+            log_level: None,
+            nodes: SERVERS
+                .iter()
+                .map(|&pid| {
+                    let peer_address = format!("127.0.0.1:{}", PEER_PORT_BASE + pid as u16);
+                    NodeConfig {
+                        pid,
+                        // the local cluster is not reachable from other hosts
+                        peer_bind: Some(peer_address.clone()),
+                        peer_address,
+                        http_address: String::from(LOCAL_HTTP_ADDRESS),
+                        http_bind: Some(String::from(LOCAL_HTTP_ADDRESS)),
+                        storage_dir: String::from(PERSIST_PATH) + &*pid.to_string(),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    pub fn node(&self, pid: NodeId) -> Option<&NodeConfig> {
+        self.nodes.iter().find(|node| node.pid == pid)
+    }
+
+    /// Where `node` keeps the log of this configuration. The first configuration uses the
+    /// node's storage directory, later ones a sibling directory each.
+    pub fn storage_dir(&self, node: &NodeConfig) -> String {
+        match self.configuration_id {
+            1 => node.storage_dir.clone(),
+            id => format!("{}_config{}", node.storage_dir, id),
+        }
+    }
+
+    pub fn peers(&self, pid: NodeId) -> Vec<NodeId> {
+        self.nodes.iter().map(|node| node.pid).filter(|&p| p != pid).collect()
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.nodes.is_empty() {
+            return Err(String::from("The cluster file lists no nodes"));
+        }
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.pid == 0 {
+                return Err(String::from("Node pids must be greater than 0"));
+            }
+            if self.nodes[..i].iter().any(|other| other.pid == node.pid) {
+                return Err(format!("Node {} is listed more than once", node.pid));
+            }
+            node.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// How this process runs the nodes of the cluster.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Deployment {
+    /// Set if the process runs only this node, otherwise it runs every node of the cluster.
+    pub node_id: Option<NodeId>,
+    pub use_tcp: bool,
+}
+
+/// Command line arguments. Without `--node-id` the whole cluster runs in this process.
+#[derive(Clone, Debug, Default)]
+pub struct Args {
+    pub node_id: Option<NodeId>,
+    pub config: Option<PathBuf>,
+    /// The REST address of a cluster member to fetch the state of the current configuration
+    /// from, for a node added to a running cluster.
+    pub join: Option<String>,
+    /// Connect the in-process nodes over TCP instead of channels.
+    pub tcp: bool,
+    /// Wipe the nodes' storage instead of recovering their state from it.
+    pub fresh: bool,
+    /// Overrides the cluster file's `log_level`.
+    pub log_level: Option<String>,
+}
+
+impl Args {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Args::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--node-id" => {
+                    let pid = args.next().ok_or("--node-id requires a value")?;
+                    parsed.node_id = Some(pid.parse().map_err(|_| format!("Invalid node id {}", pid))?);
+                }
+                "--config" => {
+                    parsed.config = Some(PathBuf::from(args.next().ok_or("--config requires a path")?));
+                }
+                "--join" => {
+                    parsed.join = Some(args.next().ok_or("--join requires an address")?);
+                }
+                "--tcp" => parsed.tcp = true,
+                "--fresh" => parsed.fresh = true,
+                "--log-level" => {
+                    parsed.log_level = Some(args.next().ok_or("--log-level requires a level")?);
+                }
+                other => return Err(format!("Unknown argument {}", other)),
+            }
+        }
+        if parsed.node_id.is_some() != parsed.config.is_some() {
+            return Err(String::from("--node-id and --config must be set together"));
+        }
+        if parsed.join.is_some() && parsed.node_id.is_none() {
+            return Err(String::from("--join requires --node-id"));
+        }
+        Ok(parsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn node(pid: NodeId, peer_address: &str) -> NodeConfig {
+        NodeConfig {
+            pid,
+            peer_address: peer_address.to_string(),
+            http_address: format!("node{}:8000", pid),
+            peer_bind: None,
+            http_bind: None,
+            storage_dir: format!("storage{}", pid),
+        }
+    }
+
+    #[test]
+    fn args_parse() {
+        let args = parse(&["--node-id", "2", "--config", "cluster.toml", "--fresh", "--log-level", "debug"]).unwrap();
+        assert_eq!(args.node_id, Some(2));
+        assert_eq!(args.config, Some(PathBuf::from("cluster.toml")));
+        assert!(args.fresh && !args.tcp);
+        assert_eq!(args.log_level.as_deref(), Some("debug"));
+        assert!(parse(&["--tcp"]).unwrap().tcp);
+
+        assert!(parse(&["--node-id", "two", "--config", "cluster.toml"]).is_err());
+        assert!(parse(&["--node-id"]).is_err());
+        assert!(parse(&["--node-id", "2"]).is_err());
+        assert!(parse(&["--join", "node1:8000"]).is_err());
+        assert!(parse(&["--verbose"]).is_err());
+    }
+
+    #[test]
+    fn load_reads_toml_and_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_path = dir.path().join("cluster.toml");
+        fs::write(&toml_path, r#"
+            forwarding = "redirect"
+            [[nodes]]
+            pid = 1
+            peer_address = "node1:8101"
+            http_address = "node1:8001"
+            http_bind = "127.0.0.1:9001"
+            storage_dir = "storage1"
+        "#).unwrap();
+        let cluster = ClusterConfig::load(&toml_path).unwrap();
+        assert_eq!(cluster.forwarding, Forwarding::Redirect);
+        assert_eq!(cluster.configuration_id, 1);
+        assert_eq!(cluster.nodes[0].peer_bind_address(), "0.0.0.0:8101");
+        assert_eq!(cluster.nodes[0].http_bind_address(), "127.0.0.1:9001");
+
+        let json_path = dir.path().join("cluster.json");
+        let nodes = serde_json::to_string(&[node(1, "node1:8101"), node(2, "[::1]:8102")]).unwrap();
+        fs::write(&json_path, format!(r#"{{"configuration_id": 3, "nodes": {}}}"#, nodes)).unwrap();
+        let cluster = ClusterConfig::load(&json_path).unwrap();
+        assert_eq!(cluster.configuration_id, 3);
+        assert_eq!(cluster.nodes, vec![node(1, "node1:8101"), node(2, "[::1]:8102")]);
+
+        let shipped = Path::new(env!("CARGO_MANIFEST_DIR")).join("cluster.toml");
+        assert_eq!(ClusterConfig::load(&shipped).unwrap().nodes.len(), 3);
+        assert!(ClusterConfig::load(&dir.path().join("missing.toml")).is_err());
+    }
+
+    #[test]
+    fn validate_rejects_invalid_nodes() {
+        let mut cluster = ClusterConfig::local();
+        assert!(cluster.validate().is_ok());
+        cluster.nodes.push(node(2, "node2:8102"));
+        assert!(cluster.validate().unwrap_err().contains("more than once"));
+        cluster.nodes.pop();
+        cluster.nodes.push(node(0, "node0:8100"));
+        assert!(cluster.validate().is_err());
+        cluster.nodes.pop();
+        for address in ["node4", "node4:port", ":8104", "node4:70000"] {
+            cluster.nodes.push(node(4, address));
+            assert!(cluster.validate().unwrap_err().contains("invalid address"), "{}", address);
+            cluster.nodes.pop();
+        }
+        cluster.nodes.clear();
+        assert!(cluster.validate().is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use std::io;
+
+use omnipaxos_core::util::LogEntry;
+use sled::Batch;
+
+use crate::kv::{ClientSession, KVEntry, KVSnapshot};
+use crate::nodes::KVStore;
+
+const APPLIED_IDX_KEY: &[u8] = b"applied_idx";
+const KEY_PREFIX: &[u8] = b"kv/";
+const SESSION_PREFIX: &[u8] = b"session/";
+
+/// A node's state machine in sled. Every applied batch of entries is written together with
+/// the index it brings the state to in one atomic batch, so after a restart the state and its
+/// index always match and only the log after that index is replayed.
+#[derive(Clone, Debug)]
+pub struct DurableState {
+    tree: sled::Tree,
+    /// Set after a failed write. The keys of the failed batch are then missing from the tree,
+    /// so the next write replaces everything.
+    dirty: bool,
+}
+
+impl DurableState {
+    pub fn open(path: String) -> sled::Result<Self> {
+        let db = sled::open(path)?;
+        Ok(DurableState { tree: db.open_tree("state")?, dirty: false })
+    }
+
+    /// The stored state, `None` if nothing was applied yet.
+    pub fn load(&self) -> sled::Result<Option<KVStore>> {
+        let applied_idx = match self.tree.get(APPLIED_IDX_KEY)? {
+            Some(bytes) => decode_u64(&bytes)?,
+            None => return Ok(None),
+        };
+        let mut kv_store = KVStore::new();
+        kv_store.decided_idx = applied_idx;
+        for item in self.tree.scan_prefix(KEY_PREFIX) {
+            let (key, value) = item?;
+            kv_store.key_value.insert(suffix(&key, KEY_PREFIX), decode_u64(&value)?);
+        }
+        for item in self.tree.scan_prefix(SESSION_PREFIX) {
+            let (client_id, session) = item?;
+            let session: ClientSession = bincode::deserialize(&session).map_err(|e| invalid(&e.to_string()))?;
+            kv_store.sessions.insert(suffix(&client_id, SESSION_PREFIX), session);
+        }
+        Ok(Some(kv_store))
+    }
+
+    /// Writes the keys and sessions that `entries` changed in `kv_store`, which has just applied
+    /// them, together with its applied index. After a failed write it writes the whole state.
+    pub fn save(&mut self, kv_store: &KVStore, entries: &[LogEntry<KVEntry, KVSnapshot>]) -> sled::Result<()> {
+        // an installed snapshot may have removed any key
+        let replaced = entries.iter().any(|entry| matches!(entry, LogEntry::Snapshotted(_)));
+        if replaced || self.dirty {
+            return self.replace(kv_store);
+        }
+        let result = self.changes(kv_store, entries).and_then(|batch| self.tree.apply_batch(batch));
+        self.dirty = result.is_err();
+        result
+    }
+
+    /// Replaces everything stored with `kv_store`.
+    pub fn replace(&mut self, kv_store: &KVStore) -> sled::Result<()> {
+        let result = self.everything(kv_store).and_then(|batch| self.tree.apply_batch(batch));
+        self.dirty = result.is_err();
+        result
+    }
+
+    fn changes(&self, kv_store: &KVStore, entries: &[LogEntry<KVEntry, KVSnapshot>]) -> sled::Result<Batch> {
+        let mut batch = Batch::default();
+        for entry in entries {
+            if let LogEntry::Decided(entry) = entry {
+                for key in entry.command.keys() {
+                    match kv_store.key_value.get(key) {
+                        Some(value) => batch.insert(prefixed(KEY_PREFIX, key), value.to_be_bytes().to_vec()),
+                        None => batch.remove(prefixed(KEY_PREFIX, key)),
+                    }
+                }
+                if let Some(client) = &entry.client {
+                    if let Some(session) = kv_store.sessions.get(&client.client_id) {
+                        batch.insert(prefixed(SESSION_PREFIX, &client.client_id), encode(session)?);
+                    }
+                }
+            }
+        }
+        batch.insert(APPLIED_IDX_KEY, kv_store.decided_idx.to_be_bytes().to_vec());
+        Ok(batch)
+    }
+
+    fn everything(&self, kv_store: &KVStore) -> sled::Result<Batch> {
+        let mut batch = Batch::default();
+        for key in self.tree.iter().keys() {
+            batch.remove(key?);
+        }
+        for (key, value) in &kv_store.key_value {
+            batch.insert(prefixed(KEY_PREFIX, key), value.to_be_bytes().to_vec());
+        }
+        for (client_id, session) in &kv_store.sessions {
+            batch.insert(prefixed(SESSION_PREFIX, client_id), encode(session)?);
+        }
+        batch.insert(APPLIED_IDX_KEY, kv_store.decided_idx.to_be_bytes().to_vec());
+        Ok(batch)
+    }
+}
+
+fn prefixed(prefix: &[u8], key: &str) -> Vec<u8> {
+    [prefix, key.as_bytes()].concat()
+}
+
+fn suffix(key: &[u8], prefix: &[u8]) -> String {
+    String::from_utf8_lossy(&key[prefix.len()..]).into_owned()
+}
+
+fn encode(session: &ClientSession) -> sled::Result<Vec<u8>> {
+    bincode::serialize(session).map_err(|e| invalid(&e.to_string()))
+}
+
+fn decode_u64(bytes: &[u8]) -> sled::Result<u64> {
+    let bytes = bytes.try_into().map_err(|_| invalid("index or value"))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn invalid(what: &str) -> sled::Error {
+    sled::Error::Io(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid state {}", what)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn put(seq: u64, key: &str, value: u64) -> LogEntry<KVEntry, KVSnapshot> {
+        LogEntry::Decided(KVEntry::put(key, value).with_client("client", seq))
+    }
+
+    fn path(dir: &tempfile::TempDir) -> String {
+        dir.path().join("state").to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn restart_loads_the_saved_state() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut kv_store = KVStore::new();
+            kv_store.attach(Some(DurableState::open(path(&dir)).unwrap()));
+            kv_store.apply_entries(&[put(1, "a", 1), put(2, "b", 2)]).unwrap();
+            kv_store.apply_entries(&[LogEntry::Decided(KVEntry::delete("a"))]).unwrap();
+        }
+        let loaded = DurableState::open(path(&dir)).unwrap().load().unwrap().unwrap();
+        assert_eq!(loaded.decided_idx, 3);
+        assert_eq!(loaded.key_value, HashMap::from([("b".to_string(), 2)]));
+        assert_eq!(loaded.sessions["client"].seq, 2);
+    }
+
+    #[test]
+    fn nothing_saved_loads_none() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(DurableState::open(path(&dir)).unwrap().load().unwrap().is_none());
+    }
+
+    #[test]
+    fn save_after_a_failed_write_stores_everything() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut durable = DurableState::open(path(&dir)).unwrap();
+        let mut kv_store = KVStore::new();
+        durable.replace(&kv_store).unwrap();
+        // "a" was applied but its batch never reached the tree
+        kv_store.apply_entries(&[put(1, "a", 1)]).unwrap();
+        durable.dirty = true;
+        kv_store.durable = Some(durable);
+        kv_store.apply_entries(&[put(2, "b", 2)]).unwrap();
+        drop(kv_store);
+
+        let loaded = DurableState::open(path(&dir)).unwrap().load().unwrap().unwrap();
+        assert_eq!(loaded.decided_idx, 2);
+        assert_eq!(loaded.key_value, HashMap::from([("a".to_string(), 1), ("b".to_string(), 2)]));
+    }
+}
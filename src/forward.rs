@@ -0,0 +1,141 @@
+use std::time::Instant;
+
+use actix_web::{HttpRequest, HttpResponse};
+use http::StatusCode;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tracing::{debug, warn};
+
+use crate::config::{Forwarding, NodeConfig};
+use crate::logging::REQUEST_ID_HEADER;
+use crate::nodes;
+use crate::{CLUSTER, OP_SERVER_HANDLERS};
+
+const FORWARDED_BY_HEADER: &str = "X-Forwarded-By";
+const LEADER_LOCATION_HEADER: &str = "Leader-Location";
+// headers that carry the request's semantics and are passed on to the leader
+const FORWARDED_HEADERS: [&str; 6] = [
+    REQUEST_ID_HEADER,
+    "Content-Type",
+    "X-Min-Decided-Idx",
+    "X-Client-Id",
+    "X-Client-Seq",
+    "X-Request-Timeout-Ms",
+];
+// headers of the leader's response that are relayed to the client, a node that lost leadership
+// answers 421 with the new leader's address
+const RELAYED_HEADERS: [&str; 2] = [LEADER_LOCATION_HEADER, "Location"];
+
+lazy_static! {
+    pub(crate) static ref CLIENT: reqwest::Client = reqwest::Client::new();
+}
+
+/// Counts a request received from a client, not from another node.
+pub fn count_request(req: &HttpRequest) {
+    if !req.headers().contains_key(FORWARDED_BY_HEADER) {
+        nodes::count_request(&OP_SERVER_HANDLERS.lock().unwrap());
+    }
+}
+
+/// Returns the response to a request that only the leader can serve if the leader runs in
+/// another process, or `None` if the request can be served here.
+pub async fn to_leader<B: Serialize>(req: &HttpRequest, body: Option<&B>, deadline: Instant) -> Option<HttpResponse> {
+    let leader = remote_leader()?;
+    let location = format!("http://{}{}", leader.http_address, req.uri());
+
+    // a request is forwarded at most once, the leader may have changed in the meantime
+    if req.headers().contains_key(FORWARDED_BY_HEADER) {
+        return Some(HttpResponse::build(StatusCode::MISDIRECTED_REQUEST)
+            .insert_header((LEADER_LOCATION_HEADER, location))
+            .content_type("application/json")
+            .json("This node is not the leader"));
+    }
+    let forwarding = CLUSTER.read().unwrap().forwarding;
+    debug!(leader = leader.pid, ?forwarding, "request needs the leader");
+    Some(match forwarding {
+        Forwarding::Redirect => HttpResponse::TemporaryRedirect()
+            .insert_header(("Location", location.clone()))
+            .insert_header((LEADER_LOCATION_HEADER, location))
+            .finish(),
+        Forwarding::Proxy => proxy(req, body, &leader, location, deadline).await,
+    })
+}
+
+/// The leader's node if it is known and not one of the nodes of this process.
+fn remote_leader() -> Option<NodeConfig> {
+    let leader_id = {
+        let handler = OP_SERVER_HANDLERS.lock().unwrap();
+        let leader_id = handler.values().next()?.omni_paxos.lock().unwrap().get_current_leader()?;
+        if handler.contains_key(&leader_id) {
+            return None;
+        }
+        leader_id
+    };
+    CLUSTER.read().unwrap().node(leader_id).cloned()
+}
+
+async fn proxy<B: Serialize>(
+    req: &HttpRequest,
+    body: Option<&B>,
+    leader: &NodeConfig,
+    location: String,
+    deadline: Instant,
+) -> HttpResponse {
+    let method = reqwest::Method::from_bytes(req.method().as_str().as_bytes()).unwrap();
+    let mut request = CLIENT
+        .request(method, location)
+        .timeout(deadline.saturating_duration_since(Instant::now()))
+        .header(FORWARDED_BY_HEADER, forwarded_by());
+    for name in FORWARDED_HEADERS {
+        if let Some(value) = req.headers().get(name) {
+            request = request.header(name, value.as_bytes());
+        }
+    }
+    if let Some(body) = body {
+        request = request.json(body);
+    }
+
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(err) => {
+            warn!(leader = leader.pid, error = %err, "forwarding to the leader failed");
+            return if err.is_timeout() {
+                HttpResponse::GatewayTimeout()
+                    .content_type("application/json")
+                    .status(StatusCode::GATEWAY_TIMEOUT)
+                    .json("The request did not complete before its deadline")
+            } else {
+                HttpResponse::BadGateway()
+                    .content_type("application/json")
+                    .status(StatusCode::BAD_GATEWAY)
+                    .json("The leader could not be reached")
+            };
+        }
+    };
+    let status = StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let content_type = response
+        .headers()
+        .get("Content-Type")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("application/json")
+        .to_string();
+    let mut relayed = HttpResponse::build(status);
+    relayed.content_type(content_type);
+    for name in RELAYED_HEADERS {
+        if let Some(value) = response.headers().get(name) {
+            relayed.insert_header((name, value.as_bytes()));
+        }
+    }
+    match response.bytes().await {
+        Ok(bytes) => relayed.body(bytes),
+        Err(_) => HttpResponse::BadGateway()
+            .content_type("application/json")
+            .status(StatusCode::BAD_GATEWAY)
+            .json("The leader's response could not be read"),
+    }
+}
+
+fn forwarded_by() -> String {
+    let handler = OP_SERVER_HANDLERS.lock().unwrap();
+    handler.keys().map(|pid| pid.to_string()).collect::<Vec<_>>().join(",")
+}
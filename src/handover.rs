@@ -0,0 +1,202 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use omnipaxos_core::util::NodeId;
+use tracing::info;
+
+use crate::config::HandoverConfig;
+use crate::network::NodeMessage;
+use crate::stats::NodeStats;
+use crate::util::GOSSIP_PERIOD;
+
+/// Tracks the request counts gossiped by a node's peers and decides when the node should
+/// take over leadership from a leader that handles fewer requests.
+pub struct Handover {
+    pid: NodeId,
+    peers: Vec<NodeId>,
+    config: HandoverConfig,
+    stats: Arc<NodeStats>,
+    priority: u64,
+    /// This node's request total at each gossip round within the window, oldest first.
+    totals: VecDeque<u64>,
+    /// The requests each peer handled within the window and its priority, as last gossiped.
+    peer_requests: HashMap<NodeId, (u64, u64)>,
+    /// The leader and since when this node has known it.
+    leader: Option<(NodeId, Instant)>,
+}
+
+impl Handover {
+    pub fn new(pid: NodeId, peers: Vec<NodeId>, config: HandoverConfig, stats: Arc<NodeStats>) -> Self {
+        Handover {
+            pid,
+            peers,
+            config,
+            stats,
+            priority: 0,
+            totals: VecDeque::new(),
+            peer_requests: HashMap::new(),
+            leader: None,
+        }
+    }
+
+    /// The requests this node handled within the window, counted from the oldest gossip round
+    /// in it. The lifetime total also counts requests from before restarts and earlier leaders.
+    fn recent_requests(&self) -> u64 {
+        let total = self.stats.requests.load(Ordering::Relaxed);
+        total.saturating_sub(self.totals.front().copied().unwrap_or(total))
+    }
+
+    pub fn gossip(&mut self) -> Vec<NodeMessage> {
+        let rounds = (self.config.window_ms / GOSSIP_PERIOD.as_millis() as u64).max(1) as usize;
+        self.totals.push_back(self.stats.requests.load(Ordering::Relaxed));
+        while self.totals.len() > rounds + 1 {
+            self.totals.pop_front();
+        }
+        let requests = self.recent_requests();
+        self.peers
+            .iter()
+            .map(|&to| NodeMessage::Requests { from: self.pid, to, requests, priority: self.priority })
+            .collect()
+    }
+
+    pub fn receive(&mut self, from: NodeId, requests: u64, priority: u64) {
+        self.peer_requests.insert(from, (requests, priority));
+    }
+
+    /// Returns the priority to raise this node's leader priority to if it should take over
+    /// from `leader`: within the window it has handled at least `threshold` more requests than
+    /// the leader and more than any other node, and the leader has been in place for at least
+    /// the cooldown.
+    pub fn check(&mut self, leader: Option<NodeId>) -> Option<u64> {
+        let leader = leader?;
+        let now = Instant::now();
+        let since = match self.leader {
+            Some((pid, since)) if pid == leader => since,
+            _ => {
+                self.leader = Some((leader, now));
+                now
+            }
+        };
+        if !self.config.enabled
+            || leader == self.pid
+            || now.duration_since(since) < Duration::from_millis(self.config.cooldown_ms)
+        {
+            return None;
+        }
+        let requests = self.recent_requests();
+        let (leader_requests, _) = *self.peer_requests.get(&leader)?;
+        // only the busiest node takes over, the lowest pid among equally busy ones, so two
+        // nodes never compete for it
+        if requests < leader_requests.saturating_add(self.config.threshold)
+            || self
+                .peer_requests
+                .iter()
+                .any(|(pid, (r, _))| *r > requests || (*r == requests && *pid < self.pid))
+        {
+            return None;
+        }
+        let highest = self.peer_requests.values().map(|(_, p)| *p).max().unwrap_or(0);
+        self.priority = highest.max(self.priority) + 1;
+        self.leader = Some((leader, now));
+        info!(
+            pid = self.pid,
+            requests,
+            leader,
+            leader_requests,
+            priority = self.priority,
+            "taking over leadership, raising priority"
+        );
+        Some(self.priority)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn node(pid: NodeId, config: HandoverConfig, dir: &tempfile::TempDir) -> Handover {
+        let stats = NodeStats::open(dir.path().join("stats").to_string_lossy().into_owned()).unwrap();
+        Handover::new(pid, vec![1, 2, 3].into_iter().filter(|&p| p != pid).collect(), config, Arc::new(stats))
+    }
+
+    fn config(threshold: u64, cooldown_ms: u64) -> HandoverConfig {
+        HandoverConfig { enabled: true, threshold, cooldown_ms, window_ms: 10_000 }
+    }
+
+    fn serve(handover: &Handover, requests: u64) {
+        handover.stats.requests.fetch_add(requests, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn takes_over_once_past_the_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut handover = node(2, config(10, 0), &dir);
+        handover.gossip();
+        handover.receive(1, 5, 0);
+        handover.receive(3, 0, 4);
+        serve(&handover, 14);
+        assert_eq!(handover.check(Some(1)), None);
+        serve(&handover, 1);
+        assert_eq!(handover.check(Some(1)), Some(5));
+        // the leader itself never takes over
+        assert_eq!(handover.check(Some(2)), None);
+    }
+
+    #[test]
+    fn waits_for_the_cooldown_after_a_leader_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut handover = node(2, config(10, 50), &dir);
+        handover.gossip();
+        handover.receive(1, 0, 0);
+        serve(&handover, 100);
+        assert_eq!(handover.check(Some(1)), None);
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(handover.check(Some(1)), Some(1));
+        // taking over restarts the cooldown
+        assert_eq!(handover.check(Some(1)), None);
+    }
+
+    #[test]
+    fn only_the_busiest_node_takes_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut handover = node(2, config(10, 0), &dir);
+        handover.gossip();
+        handover.receive(1, 0, 0);
+        handover.receive(3, 50, 0);
+        serve(&handover, 40);
+        assert_eq!(handover.check(Some(1)), None);
+        // between equally busy nodes the lower pid takes over
+        serve(&handover, 10);
+        assert_eq!(handover.check(Some(1)), Some(1));
+        let dir = tempfile::tempdir().unwrap();
+        let mut other = node(3, config(10, 0), &dir);
+        other.gossip();
+        other.receive(1, 0, 0);
+        other.receive(2, 50, 0);
+        serve(&other, 50);
+        assert_eq!(other.check(Some(1)), None);
+    }
+
+    #[test]
+    fn past_requests_do_not_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut handover = node(2, config(10, 0), &dir);
+        // a total from before a restart, the node has handled nothing since
+        serve(&handover, 1000);
+        handover.gossip();
+        handover.receive(1, 20, 0);
+        assert_eq!(handover.check(Some(1)), None);
+        assert!(matches!(handover.gossip()[0], NodeMessage::Requests { requests: 0, .. }));
+        // a window of one gossip round only counts the requests since the previous one
+        let dir = tempfile::tempdir().unwrap();
+        let mut handover = node(2, HandoverConfig { window_ms: 0, ..config(10, 0) }, &dir);
+        handover.gossip();
+        serve(&handover, 30);
+        assert!(matches!(handover.gossip()[0], NodeMessage::Requests { requests: 30, .. }));
+        assert!(matches!(handover.gossip()[0], NodeMessage::Requests { requests: 0, .. }));
+    }
+}
@@ -1,43 +1,428 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
 
 use omnipaxos_core::storage::Snapshot;
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)] // Clone and Debug are required traits.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)] // Clone and Debug are required traits.
 pub struct KeyValue {
     pub key: String,
     pub value: u64,
 }
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct KeyValueCas {
     pub key: String,
     pub old_value: u64,
     pub new_value: u64,
 }
 
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct KeyValueIncrement {
+    pub key: String,
+    pub delta: u64,
+}
+
+/// A state machine transition replicated through the OmniPaxos log.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum KVCommand {
+    Put(KeyValue),
+    Delete(String),
+    Cas(KeyValueCas),
+    Increment(KeyValueIncrement),
+    /// Commands applied one after another within a single log entry.
+    Batch(Vec<KVCommand>),
+    /// Leaves the state untouched, appended by reads to confirm leadership.
+    ReadBarrier,
+    /// Loads the imported state into a store without keys, refused if it holds any.
+    Restore(HashMap<String, u64>),
+}
+
+/// The result of applying a `KVCommand`, identical on every replica.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum KVOutcome {
+    Written { key: String, value: u64 },
+    /// `value` is what the key held before the delete, `None` if it did not exist.
+    Deleted { key: String, value: Option<u64> },
+    /// The CAS did not match, `current` is the value the key had when it was applied.
+    CasFailed { key: String, current: Option<u64> },
+    Batch(Vec<KVOutcome>),
+    Noop,
+    /// The restore found `keys` keys in the store and left it untouched.
+    RestoreRefused { keys: u64 },
+    /// The client already had the later sequence number `seq` applied.
+    Superseded { seq: u64 },
+    /// The client already had this sequence number applied, at `decided_idx` with `outcome`.
+    Duplicate { decided_idx: u64, outcome: Box<KVOutcome> },
+}
+
+impl KVCommand {
+    pub fn apply(&self, key_value: &mut HashMap<String, u64>) -> KVOutcome {
+        match self {
+            KVCommand::Put(KeyValue { key, value }) => {
+                key_value.insert(key.clone(), *value);
+                KVOutcome::Written { key: key.clone(), value: *value }
+            }
+            KVCommand::Delete(key) => {
+                KVOutcome::Deleted { key: key.clone(), value: key_value.remove(key) }
+            }
+            KVCommand::Cas(KeyValueCas { key, old_value, new_value }) => {
+                match key_value.get(key) {
+                    Some(current) if current == old_value => {
+                        key_value.insert(key.clone(), *new_value);
+                        KVOutcome::Written { key: key.clone(), value: *new_value }
+                    }
+                    current => KVOutcome::CasFailed { key: key.clone(), current: current.copied() },
+                }
+            }
+            KVCommand::Increment(KeyValueIncrement { key, delta }) => {
+                let value = key_value.get(key).copied().unwrap_or(0).saturating_add(*delta);
+                key_value.insert(key.clone(), value);
+                KVOutcome::Written { key: key.clone(), value }
+            }
+            KVCommand::Batch(commands) => {
+                KVOutcome::Batch(commands.iter().map(|c| c.apply(key_value)).collect())
+            }
+            KVCommand::ReadBarrier => KVOutcome::Noop,
+            KVCommand::Restore(_) if !key_value.is_empty() => {
+                KVOutcome::RestoreRefused { keys: key_value.len() as u64 }
+            }
+            KVCommand::Restore(restored) => {
+                *key_value = restored.clone();
+                KVOutcome::Noop
+            }
+        }
+    }
+
+    /// Keys whose value before this command affects its outcome.
+    fn read_keys(&self) -> Vec<&str> {
+        match self {
+            KVCommand::Delete(key) => vec![key.as_str()],
+            KVCommand::Cas(kv) => vec![kv.key.as_str()],
+            KVCommand::Increment(kv) => vec![kv.key.as_str()],
+            KVCommand::Batch(commands) => commands.iter().flat_map(|c| c.read_keys()).collect(),
+            KVCommand::Put(_) | KVCommand::ReadBarrier | KVCommand::Restore(_) => vec![],
+        }
+    }
+
+    pub(crate) fn keys(&self) -> Vec<&str> {
+        match self {
+            KVCommand::Put(kv) => vec![kv.key.as_str()],
+            KVCommand::Batch(commands) => commands.iter().flat_map(|c| c.keys()).collect(),
+            KVCommand::Restore(restored) => restored.keys().map(String::as_str).collect(),
+            _ => self.read_keys(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ClientRequest {
+    pub client_id: String,
+    pub seq: u64,
+}
+
+/// The latest request applied for a client, used to answer retries without re-applying them.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ClientSession {
+    pub seq: u64,
+    /// Within a `KVSnapshot`, counted from the snapshot's first entry until it is installed.
+    pub decided_idx: u64,
+    pub outcome: KVOutcome,
+}
+
+/// The entry replicated through the OmniPaxos log.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct KVEntry {
+    /// Random identifier the proposer uses to recognize its own entry once it is decided.
+    pub proposal_id: u64,
+    /// Set for writes that must be applied at most once per client sequence number.
+    pub client: Option<ClientRequest>,
+    pub command: KVCommand,
+}
+
+impl KVEntry {
+    pub fn apply(
+        &self,
+        decided_idx: u64,
+        key_value: &mut HashMap<String, u64>,
+        sessions: &mut HashMap<String, ClientSession>,
+    ) -> KVOutcome {
+        let ClientRequest { client_id, seq } = match &self.client {
+            Some(client) => client,
+            None => return self.command.apply(key_value),
+        };
+        if let Some(session) = sessions.get(client_id) {
+            if session.seq > *seq {
+                return KVOutcome::Superseded { seq: session.seq };
+            }
+            if session.seq == *seq {
+                return KVOutcome::Duplicate {
+                    decided_idx: session.decided_idx,
+                    outcome: Box::new(session.outcome.clone()),
+                };
+            }
+        }
+        let outcome = self.command.apply(key_value);
+        sessions.insert(client_id.clone(), ClientSession {
+            seq: *seq,
+            decided_idx,
+            outcome: outcome.clone(),
+        });
+        outcome
+    }
+}
+
+#[cfg(test)]
+impl KVEntry {
+    pub fn put(key: &str, value: u64) -> Self {
+        KVEntry { proposal_id: 0, client: None, command: KVCommand::Put(KeyValue { key: key.to_string(), value }) }
+    }
+
+    pub fn delete(key: &str) -> Self {
+        KVEntry { proposal_id: 0, client: None, command: KVCommand::Delete(key.to_string()) }
+    }
+
+    /// The same entry sent as request `seq` of client `client_id`.
+    pub fn with_client(self, client_id: &str, seq: u64) -> Self {
+        KVEntry { client: Some(ClientRequest { client_id: client_id.to_string(), seq }), ..self }
+    }
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct KVSnapshot {
     pub snapshotted: HashMap<String, u64>,
+    /// Tombstones for keys deleted within this snapshot, so merging it removes them.
+    pub deleted: HashSet<String>,
+    pub sessions: HashMap<String, ClientSession>,
+    /// Entries, in log order and with their offset, whose effect depends on the state preceding
+    /// this snapshot. They are replayed on top of it when the snapshot is merged or restored.
+    pub pending: Vec<(u64, KVEntry)>,
+    /// How many log entries the snapshot covers, so merging a delta after it can rebase the
+    /// delta's session indices.
+    #[serde(default)]
+    pub len: u64,
+    /// Set if the snapshot holds a store's whole state, it then replaces the preceding state
+    /// entirely.
+    #[serde(default)]
+    pub replaces: bool,
+}
+
+impl KVSnapshot {
+    /// The full state of a store that has applied the log up to `decided_idx`.
+    pub fn of(key_value: &HashMap<String, u64>, sessions: &HashMap<String, ClientSession>, decided_idx: u64) -> Self {
+        KVSnapshot {
+            snapshotted: key_value.clone(),
+            deleted: HashSet::new(),
+            sessions: sessions.clone(),
+            pending: Vec::new(),
+            len: decided_idx,
+            replaces: true,
+        }
+    }
+
+    /// The key-value pairs of a snapshot that covers the log from its start.
+    pub fn into_state(mut self) -> HashMap<String, u64> {
+        self.resolve();
+        self.snapshotted
+    }
+
+    /// Records `entry`, the `offset`th one the snapshot covers.
+    fn record(&mut self, offset: u64, entry: &KVEntry) {
+        if self.depends_on_previous(entry) {
+            self.pending.push((offset, entry.clone()));
+        } else {
+            self.apply(offset, entry);
+        }
+    }
+
+    fn depends_on_previous(&self, entry: &KVEntry) -> bool {
+        // a restore depends on whether any key exists, and everything after it on the restore
+        let restores = |command: &KVCommand| matches!(command, KVCommand::Restore(_));
+        if (!self.replaces && restores(&entry.command)) || self.pending.iter().any(|(_, p)| restores(&p.command)) {
+            return true;
+        }
+        let keys = entry.command.keys();
+        let client_id = entry.client.as_ref().map(|c| &c.client_id);
+        let touched_by_pending = self.pending.iter().any(|(_, p)| {
+            p.command.keys().iter().any(|k| keys.contains(k))
+                || (client_id.is_some() && p.client.as_ref().map(|c| &c.client_id) == client_id)
+        });
+        let unknown_client = match &entry.client {
+            Some(client) => !self.sessions.contains_key(&client.client_id),
+            None => false,
+        };
+        let unknown_key = entry
+            .command
+            .read_keys()
+            .iter()
+            .any(|k| !self.replaces && !self.snapshotted.contains_key(*k) && !self.deleted.contains(*k));
+        touched_by_pending || unknown_client || unknown_key
+    }
+
+    fn apply(&mut self, offset: u64, entry: &KVEntry) {
+        entry.apply(offset, &mut self.snapshotted, &mut self.sessions);
+        for k in entry.command.keys() {
+            if self.snapshotted.contains_key(k) {
+                self.deleted.remove(k);
+            } else {
+                self.deleted.insert(k.to_string());
+            }
+        }
+    }
+
+    /// Replays the pending entries against an empty preceding state. Only valid for a
+    /// snapshot that starts at the beginning of the log.
+    pub(crate) fn resolve(&mut self) {
+        for (offset, entry) in std::mem::take(&mut self.pending) {
+            self.apply(offset, &entry);
+        }
+    }
 }
 
-impl Snapshot<KeyValue> for KVSnapshot {
-    fn create(entries: &[KeyValue]) -> Self {
-        let mut snapshotted = HashMap::new();
-        for e in entries {
-            let KeyValue { key, value } = e;
-            snapshotted.insert(key.clone(), *value);
+impl Snapshot<KVEntry> for KVSnapshot {
+    fn create(entries: &[KVEntry]) -> Self {
+        let mut snapshot = Self {
+            snapshotted: HashMap::new(),
+            deleted: HashSet::new(),
+            sessions: HashMap::new(),
+            pending: Vec::new(),
+            len: entries.len() as u64,
+            replaces: false,
+        };
+        for (i, e) in entries.iter().enumerate() {
+            snapshot.record(i as u64 + 1, e);
         }
-        Self { snapshotted }
+        snapshot
     }
 
     fn merge(&mut self, delta: Self) {
+        // the snapshot being merged into always covers the log from its start
+        self.resolve();
+        // the delta's offsets continue after this snapshot, unless it is a whole state
+        let base = if delta.replaces { 0 } else { self.len };
+        if delta.replaces {
+            self.snapshotted.clear();
+            self.deleted.clear();
+        }
+        for k in delta.deleted {
+            self.snapshotted.remove(&k);
+            self.deleted.insert(k);
+        }
         for (k, v) in delta.snapshotted {
+            self.deleted.remove(&k);
             self.snapshotted.insert(k, v);
         }
+        for (c, mut session) in delta.sessions {
+            session.decided_idx += base;
+            self.sessions.insert(c, session);
+        }
+        for (offset, e) in delta.pending {
+            self.record(base + offset, &e);
+        }
+        self.len = base + delta.len;
     }
 
     fn use_snapshots() -> bool {
         true
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nodes::KVStore;
+
+    fn state(pairs: &[(&str, u64)]) -> HashMap<String, u64> {
+        pairs.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+    }
+
+    fn merged(base: &[KVEntry], delta: &[KVEntry]) -> HashMap<String, u64> {
+        let mut snapshot = KVSnapshot::create(base);
+        snapshot.merge(KVSnapshot::create(delta));
+        snapshot.into_state()
+    }
+
+    #[test]
+    fn delete_leaves_a_tombstone() {
+        let delta = KVSnapshot::create(&[KVEntry::put("a", 1), KVEntry::delete("a")]);
+        assert!(delta.snapshotted.is_empty());
+        assert!(delta.deleted.contains("a"));
+        assert_eq!(
+            merged(&[KVEntry::put("a", 1), KVEntry::put("b", 2)], &[KVEntry::put("a", 1), KVEntry::delete("a")]),
+            state(&[("b", 2)])
+        );
+    }
+
+    #[test]
+    fn delete_of_a_key_unknown_to_the_delta_is_replayed() {
+        let delta = KVSnapshot::create(&[KVEntry::delete("a")]);
+        assert_eq!(delta.pending.len(), 1);
+        assert_eq!(merged(&[KVEntry::put("a", 1)], &[KVEntry::delete("a")]), state(&[]));
+        assert_eq!(merged(&[KVEntry::put("b", 1)], &[KVEntry::delete("a")]), state(&[("b", 1)]));
+        assert_eq!(KVSnapshot::create(&[KVEntry::delete("a")]).into_state(), state(&[]));
+    }
+
+    #[test]
+    fn delete_then_put_keeps_the_put() {
+        assert_eq!(merged(&[KVEntry::put("a", 1)], &[KVEntry::delete("a"), KVEntry::put("a", 5)]), state(&[("a", 5)]));
+        assert_eq!(merged(&[], &[KVEntry::delete("a"), KVEntry::put("a", 5)]), state(&[("a", 5)]));
+    }
+
+    #[test]
+    fn restore_mid_delta_depends_on_the_preceding_state() {
+        let restore = || KVEntry { proposal_id: 0, client: None, command: KVCommand::Restore(state(&[("r", 1)])) };
+        let delta = KVSnapshot::create(&[restore(), KVEntry::put("c", 3)]);
+        // the restore and everything after it wait for the preceding state
+        assert_eq!(delta.pending.len(), 2);
+        assert_eq!(merged(&[], &[restore(), KVEntry::put("c", 3)]), state(&[("r", 1), ("c", 3)]));
+        assert_eq!(merged(&[KVEntry::put("k", 1)], &[restore(), KVEntry::put("c", 3)]), state(&[("k", 1), ("c", 3)]));
+        // a key written earlier in the same delta refuses it as well
+        assert_eq!(merged(&[], &[KVEntry::put("b", 2), restore(), KVEntry::put("c", 3)]), state(&[("b", 2), ("c", 3)]));
+    }
+
+    #[test]
+    fn earlier_sequence_number_is_superseded() {
+        let mut key_value = HashMap::new();
+        let mut sessions = HashMap::new();
+        KVEntry::put("a", 2).with_client("c", 2).apply(1, &mut key_value, &mut sessions);
+        assert_eq!(
+            KVEntry::put("a", 1).with_client("c", 1).apply(2, &mut key_value, &mut sessions),
+            KVOutcome::Superseded { seq: 2 }
+        );
+        assert_eq!(
+            KVEntry::put("a", 2).with_client("c", 2).apply(3, &mut key_value, &mut sessions),
+            KVOutcome::Duplicate {
+                decided_idx: 1,
+                outcome: Box::new(KVOutcome::Written { key: "a".to_string(), value: 2 }),
+            }
+        );
+        assert_eq!(key_value["a"], 2);
+    }
+
+    #[test]
+    fn snapshot_sessions_keep_their_decided_index() {
+        let mut snapshot = KVSnapshot::create(&[
+            KVEntry::put("a", 1).with_client("c", 1),
+            KVEntry::put("b", 1).with_client("d", 1),
+        ]);
+        snapshot.merge(KVSnapshot::create(&[
+            KVEntry::put("a", 2).with_client("e", 1),
+            KVEntry::put("b", 2).with_client("c", 2),
+        ]));
+        let mut kv_store = KVStore::new();
+        kv_store.install(4, snapshot);
+        assert_eq!(kv_store.sessions["d"].decided_idx, 2);
+        assert_eq!(kv_store.sessions["e"].decided_idx, 3);
+        assert_eq!(kv_store.sessions["c"].decided_idx, 4);
+    }
+
+    #[test]
+    fn pending_sessions_are_rebased_when_merged() {
+        let mut snapshot = KVSnapshot::create(&[KVEntry::put("a", 1).with_client("c", 1)]);
+        // "d" is unknown to the delta, so its entry is pending until merged
+        let delta = KVSnapshot::create(&[KVEntry::put("b", 1).with_client("d", 5)]);
+        assert_eq!(delta.pending.len(), 1);
+        snapshot.merge(delta);
+        let mut kv_store = KVStore::new();
+        kv_store.install(2, snapshot);
+        assert_eq!(kv_store.sessions["d"].decided_idx, 2);
+    }
+}
@@ -1,25 +1,46 @@
+use std::time::{Duration, Instant};
+
+use actix_web::delete;
 use actix_web::get;
-use actix_web::HttpResponse;
+use actix_web::{HttpRequest, HttpResponse};
 use actix_web::post;
-use actix_web::web::{Json, Path};
+use actix_web::web::{Json, Path, Query};
 use http::StatusCode;
 use serde::Deserialize;
+use tracing::debug;
 
 use storage::create_kv;
 
-use crate::{KeyValue, storage};
-use crate::kv::KeyValueCas;
-use crate::storage::{cas_kv, get_kv};
+use crate::{forward, KeyValue, storage};
+use crate::kv::{ClientRequest, KeyValueCas, KVOutcome};
+use crate::storage::{cas_kv, delete_kv, get_kv, ReadConsistency, StorageError};
+use crate::util::REQUEST_TIMEOUT;
+
+const MIN_DECIDED_IDX_HEADER: &str = "X-Min-Decided-Idx";
+const CLIENT_ID_HEADER: &str = "X-Client-Id";
+const CLIENT_SEQ_HEADER: &str = "X-Client-Seq";
+const REQUEST_TIMEOUT_HEADER: &str = "X-Request-Timeout-Ms";
 
 #[post("/key-value")]
-pub async fn create(kv_req: Json<KeyValue>) -> HttpResponse {
+pub async fn create(req: HttpRequest, kv_req: Json<KeyValue>) -> HttpResponse {
+    forward::count_request(&req);
+    let (client, deadline) = match client_request(&req).and_then(|c| Ok((c, deadline(&req)?))) {
+        Ok(write) => write,
+        Err(error) => return bad_request(error),
+    };
+    if let Some(response) = forward::to_leader(&req, Some(&*kv_req), deadline).await {
+        return response;
+    }
     let kv = KeyValue {
         key: String::from(&kv_req.key),
         value: kv_req.value,
     };
 
-    let decided_idx = create_kv(kv.clone()).await;
-    println!("decided_idx: {:?}", decided_idx);
+    let decided_idx = match create_kv(kv.clone(), client, deadline).await {
+        Ok(decided_idx) => decided_idx,
+        Err(error) => return storage_error(error),
+    };
+    debug!(decided_idx, "created");
 
     let response = KeyValueResponse {
         key: kv.key,
@@ -34,37 +55,74 @@ pub async fn create(kv_req: Json<KeyValue>) -> HttpResponse {
 }
 
 #[post("/key-value/cas")]
-pub async fn cas(kv_req: Json<KeyValueCas>) -> HttpResponse {
+pub async fn cas(req: HttpRequest, kv_req: Json<KeyValueCas>) -> HttpResponse {
+    forward::count_request(&req);
+    let (client, deadline) = match client_request(&req).and_then(|c| Ok((c, deadline(&req)?))) {
+        Ok(write) => write,
+        Err(error) => return bad_request(error),
+    };
+    if let Some(response) = forward::to_leader(&req, Some(&*kv_req), deadline).await {
+        return response;
+    }
     let kv = KeyValueCas {
         key: String::from(&kv_req.key),
         old_value: kv_req.old_value,
         new_value: kv_req.new_value
     };
 
-    let decided_idx = cas_kv(kv.clone()).await;
-    println!("CAS decided_idx: {:?}", decided_idx);
+    let (decided_idx, outcome) = match cas_kv(kv.clone(), client, deadline).await {
+        Ok(cas) => cas,
+        Err(error) => return storage_error(error),
+    };
+    debug!(decided_idx, ?outcome, "compared and set");
 
-    if decided_idx == 0 {
-        HttpResponse::BadRequest()
-            .content_type("application/json")
-            .status(StatusCode::BAD_REQUEST)
-            .json("Could not CAS KV since the old value was different")
-    } else {
-        let response = KeyValueResponse {
-            key: kv.key,
-            value: kv.old_value,
-            decided_idx,
-        };
-        HttpResponse::Ok()
-            .content_type("application/json")
-            .status(StatusCode::OK)
-            .json(response)
+    match outcome {
+        KVOutcome::CasFailed { key, current } => {
+            let response = CasFailedResponse {
+                key,
+                current_value: current,
+                decided_idx,
+                error: String::from("Could not CAS KV since the old value was different"),
+            };
+            HttpResponse::BadRequest()
+                .content_type("application/json")
+                .status(StatusCode::BAD_REQUEST)
+                .json(response)
+        }
+        _ => {
+            let response = KeyValueResponse {
+                key: kv.key,
+                value: kv.old_value,
+                decided_idx,
+            };
+            HttpResponse::Ok()
+                .content_type("application/json")
+                .status(StatusCode::OK)
+                .json(response)
+        }
     }
 }
 
 #[get("/key-value/{key}")]
-pub async fn get(key: Path<String>) -> HttpResponse {
-    let response = get_kv(key.into_inner()).await;
+pub async fn get(req: HttpRequest, key: Path<String>, params: Query<ReadParams>) -> HttpResponse {
+    forward::count_request(&req);
+    let read = params
+        .read_consistency()
+        .and_then(|consistency| Ok((consistency, params.min_decided_idx(&req)?, deadline(&req)?)));
+    let (consistency, min_decided_idx, deadline) = match read {
+        Ok(read) => read,
+        Err(error) => return bad_request(error),
+    };
+    // stale reads are served by any node, all others need the leader's state
+    if consistency != ReadConsistency::Stale {
+        if let Some(response) = forward::to_leader::<()>(&req, None, deadline).await {
+            return response;
+        }
+    }
+    let response = match get_kv(key.into_inner(), consistency, min_decided_idx, deadline).await {
+        Ok(response) => response,
+        Err(error) => return storage_error(error),
+    };
 
     return if response.key.is_empty() {
         HttpResponse::NotFound()
@@ -79,9 +137,145 @@ pub async fn get(key: Path<String>) -> HttpResponse {
     };
 }
 
+#[delete("/key-value/{key}")]
+pub async fn remove(req: HttpRequest, key: Path<String>) -> HttpResponse {
+    forward::count_request(&req);
+    let (client, deadline) = match client_request(&req).and_then(|c| Ok((c, deadline(&req)?))) {
+        Ok(write) => write,
+        Err(error) => return bad_request(error),
+    };
+    if let Some(response) = forward::to_leader::<()>(&req, None, deadline).await {
+        return response;
+    }
+    let (decided_idx, outcome) = match delete_kv(key.into_inner(), client, deadline).await {
+        Ok(delete) => delete,
+        Err(error) => return storage_error(error),
+    };
+    debug!(decided_idx, ?outcome, "deleted");
+
+    match outcome {
+        KVOutcome::Deleted { key, value: Some(value) } => {
+            let response = KeyValueResponse {
+                key,
+                value,
+                decided_idx,
+            };
+            HttpResponse::Ok()
+                .content_type("application/json")
+                .status(StatusCode::OK)
+                .json(response)
+        }
+        _ => HttpResponse::NotFound()
+            .content_type("application/json")
+            .status(StatusCode::NOT_FOUND)
+            .json("The key did not exist when the delete was applied"),
+    }
+}
+
+/// Reads the optional client id and sequence number that make a write exactly-once.
+fn client_request(req: &HttpRequest) -> Result<Option<ClientRequest>, String> {
+    let header = |name| req.headers().get(name).map(|h| h.to_str().unwrap_or_default());
+    match (header(CLIENT_ID_HEADER), header(CLIENT_SEQ_HEADER)) {
+        (None, None) => Ok(None),
+        (Some(client_id), Some(seq)) if !client_id.is_empty() => match seq.parse() {
+            Ok(seq) => Ok(Some(ClientRequest { client_id: client_id.to_string(), seq })),
+            Err(_) => Err(format!("Invalid {} header", CLIENT_SEQ_HEADER)),
+        },
+        _ => Err(format!("{} and {} must be set together", CLIENT_ID_HEADER, CLIENT_SEQ_HEADER)),
+    }
+}
+
+/// The request's deadline, `X-Request-Timeout-Ms` from now or `REQUEST_TIMEOUT` by default.
+pub(crate) fn deadline(req: &HttpRequest) -> Result<Instant, String> {
+    let timeout = match req.headers().get(REQUEST_TIMEOUT_HEADER) {
+        Some(header) => header
+            .to_str()
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .map(Duration::from_millis)
+            .ok_or_else(|| format!("Invalid {} header", REQUEST_TIMEOUT_HEADER))?,
+        None => REQUEST_TIMEOUT,
+    };
+    Ok(Instant::now() + timeout)
+}
+
+pub(crate) fn storage_error(error: StorageError) -> HttpResponse {
+    match error {
+        StorageError::Timeout => HttpResponse::GatewayTimeout()
+            .content_type("application/json")
+            .status(StatusCode::GATEWAY_TIMEOUT)
+            .json("The request did not complete before its deadline"),
+        StorageError::Unavailable => HttpResponse::ServiceUnavailable()
+            .content_type("application/json")
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .json("No leader is available to serve the request"),
+        StorageError::Superseded => HttpResponse::Conflict()
+            .content_type("application/json")
+            .status(StatusCode::CONFLICT)
+            .json("A later sequence number of this client was already applied"),
+        StorageError::TooLarge => HttpResponse::PayloadTooLarge()
+            .content_type("application/json")
+            .status(StatusCode::PAYLOAD_TOO_LARGE)
+            .json("The request is too large to replicate between the nodes"),
+    }
+}
+
+pub(crate) fn bad_request(error: String) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .content_type("application/json")
+        .status(StatusCode::BAD_REQUEST)
+        .json(error)
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReadParams {
+    /// One of `linearizable` (default), `lease`, `leader`, `stale` or `bounded`.
+    pub consistency: Option<String>,
+    /// Required by `bounded`, how many entries the serving replica may lag behind the leader.
+    pub max_lag: Option<u64>,
+    /// A `decided_idx` returned by an earlier request that the read must observe, can also be
+    /// passed in the `X-Min-Decided-Idx` header.
+    pub min_decided_idx: Option<u64>,
+}
+
+impl ReadParams {
+    fn read_consistency(&self) -> Result<ReadConsistency, String> {
+        match (self.consistency.as_deref(), self.max_lag) {
+            (None, _) | (Some("linearizable"), _) => Ok(ReadConsistency::Linearizable),
+            (Some("lease"), _) => Ok(ReadConsistency::Lease),
+            (Some("leader"), _) => Ok(ReadConsistency::Leader),
+            (Some("stale"), _) => Ok(ReadConsistency::Stale),
+            (Some("bounded"), Some(max_lag)) => Ok(ReadConsistency::Bounded(max_lag)),
+            (Some("bounded"), None) => Err(String::from("Bounded consistency requires max_lag")),
+            (Some(other), _) => Err(format!("Unknown consistency level {}", other)),
+        }
+    }
+
+    fn min_decided_idx(&self, req: &HttpRequest) -> Result<Option<u64>, String> {
+        match req.headers().get(MIN_DECIDED_IDX_HEADER) {
+            Some(header) => header
+                .to_str()
+                .ok()
+                .and_then(|idx| idx.parse().ok())
+                .map(Some)
+                .ok_or_else(|| format!("Invalid {} header", MIN_DECIDED_IDX_HEADER)),
+            None => Ok(self.min_decided_idx),
+        }
+    }
+}
+
 #[derive(Clone, Debug, serde::Serialize, Deserialize)]
 pub struct KeyValueResponse {
     pub key: String,
     pub value: u64,
     pub decided_idx: u64,
-}
\ No newline at end of file
+}
+
+#[derive(Clone, Debug, serde::Serialize, Deserialize)]
+pub struct CasFailedResponse {
+    pub key: String,
+    /// The value observed when the CAS was applied, `None` if the key did not exist.
+    pub current_value: Option<u64>,
+    pub decided_idx: u64,
+    pub error: String,
+}
@@ -0,0 +1,47 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::Error;
+use futures::future::LocalBoxFuture;
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Logs at `level` unless `RUST_LOG` is set, which takes precedence and allows per-module filters.
+pub fn init(level: &str) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+}
+
+/// Runs the request inside a span carrying its request id, taken from the `X-Request-Id`
+/// header so that it is the same on every node a request passes, or generated otherwise.
+/// The id is echoed in the response.
+pub fn trace_request<S>(mut req: ServiceRequest, srv: &S) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static,
+{
+    let request_id = match req.headers().get(REQUEST_ID_HEADER).and_then(|id| id.to_str().ok()) {
+        Some(id) => id.to_string(),
+        None => format!("{:016x}", rand::random::<u64>()),
+    };
+    let header = HeaderValue::from_str(&request_id).unwrap_or_else(|_| HeaderValue::from_static("invalid"));
+    req.headers_mut().insert(HeaderName::from_static("x-request-id"), header.clone());
+
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.path(),
+    );
+    let response = span.in_scope(|| srv.call(req));
+    Box::pin(
+        async move {
+            let mut response = response.await?;
+            tracing::debug!(status = response.status().as_u16(), "request completed");
+            response.headers_mut().insert(HeaderName::from_static("x-request-id"), header);
+            Ok(response)
+        }
+        .instrument(span),
+    )
+}
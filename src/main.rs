@@ -1,22 +1,31 @@
-use std::{collections::HashMap, collections::HashSet, fs, sync::{Arc, Mutex}};
+use std::{collections::HashMap, fs, path::Path, sync::{Arc, Mutex, RwLock}};
 
-use actix_web::{App, HttpServer};
+use actix_web::{web, App, HttpServer};
 use lazy_static::lazy_static;
 use omnipaxos_core::{
-    messages::Message,
     omni_paxos::*,
     util::NodeId,
 };
 use omnipaxos_storage::persistent_storage::PersistentStorage;
-use tokio::{runtime::Builder, runtime::Runtime, sync::mpsc, time};
-use tokio::task::JoinHandle;
+use tokio::{runtime::Builder, runtime::Runtime, sync::watch, task::JoinHandle, time};
+use tracing::{error, info, warn};
 
 use crate::{
-    kv::{KeyValue, KVSnapshot},
-    server::OmniPaxosServer,
+    kv::{KeyValue, KVEntry, KVSnapshot},
+    server::{configure_persistent_storage, OmniPaxosServer},
     util::*,
 };
-use crate::kv_controller::{cas, create, get};
+use crate::admin_controller::{add_node, configuration, remove_node};
+use crate::config::{Args, ClusterConfig, Deployment, NodeConfig, StateMachine};
+use crate::durable_state::DurableState;
+use crate::compaction::Compaction;
+use crate::handover::Handover;
+use crate::kv_controller::{cas, create, get, remove};
+use crate::network::{ChannelTransport, TcpTransport, Transport};
+use crate::nodes::{KVStore, NodeHandler, Proposals};
+use crate::stats::NodeStats;
+use crate::snapshot_controller::{get_snapshot, post_snapshot};
+use crate::stats_controller::{cluster_stats, node_stats, prometheus_metrics};
 
 mod kv;
 mod server;
@@ -24,21 +33,40 @@ mod util;
 mod kv_controller;
 mod storage;
 mod nodes;
+mod network;
+mod config;
+mod forward;
+mod handover;
+mod compaction;
+mod snapshot_chain;
+mod durable_state;
+mod stats;
+mod stats_controller;
+mod metrics;
+mod logging;
+mod membership;
+mod admin_controller;
+mod snapshot_controller;
 
-type OmniPaxosKV = OmniPaxos<KeyValue, KVSnapshot, PersistentStorage<KeyValue, KVSnapshot>>;
+type OmniPaxosKV = OmniPaxos<KVEntry, KVSnapshot, PersistentStorage<KVEntry, KVSnapshot>>;
 
 const SERVERS: [u64; 3] = [1, 2, 3];
 const PERSIST_PATH: &str = "storage";
+const DEFAULT_LOG_LEVEL: &str = "info";
+// in the local cluster node `pid` listens for its peers on PEER_PORT_BASE + pid
+const PEER_PORT_BASE: u16 = 8100;
+// where the local cluster serves the REST API
+const LOCAL_HTTP_ADDRESS: &str = "127.0.0.1:8000";
 
 lazy_static! {
-    static ref OP_SERVER_HANDLERS: Mutex<HashMap<u64, (Arc<Mutex<OmniPaxosKV>>, JoinHandle<()>, OmniPaxosConfig)>> = {
+    static ref OP_SERVER_HANDLERS: Mutex<HashMap<u64, NodeHandler>> = {
         let map = HashMap::new();
         Mutex::new(map)
     };
-    static ref TO_RECOVER: Mutex<HashSet<NodeId>> = {
-        let list = HashSet::new();
-        Mutex::new(list)
-    };
+    // the layout of the cluster this process runs nodes of
+    static ref CLUSTER: RwLock<ClusterConfig> = RwLock::new(ClusterConfig::local());
+    // which nodes of the cluster this process runs and how they talk to each other
+    static ref DEPLOYMENT: RwLock<Deployment> = RwLock::new(Deployment::default());
     static ref RUNTIME: Runtime = {
         let runtime = Builder::new_multi_thread()
         .worker_threads(8)
@@ -51,137 +79,259 @@ lazy_static! {
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let args = Args::parse(std::env::args().skip(1)).map_err(invalid_input)?;
+
+    let joined = match &args.join {
+        Some(address) => Some(membership::fetch_state(address).await.map_err(invalid_input)?),
+        None => None,
+    };
 
-    // Clean-up storage
-    cleanup();
+    // either one node of the cluster in the file, or the whole local cluster in this process
+    let (cluster, local_nodes, http_address, use_tcp) = match args.node_id {
+        Some(pid) => {
+            let mut cluster = ClusterConfig::load(args.config.as_ref().unwrap()).map_err(invalid_input)?;
+            // a node removed since still serves its address, without running a replica
+            let listed = cluster.node(pid).cloned();
+            // a node added at runtime takes the current membership from the node it joins through
+            if let Some(state) = &joined {
+                cluster.configuration_id = state.configuration_id;
+                cluster.nodes = state.nodes.clone();
+            } else if !args.fresh {
+                membership::restore(&mut cluster);
+            }
+            let http_address = cluster
+                .node(pid)
+                .or(listed.as_ref())
+                .ok_or_else(|| invalid_input(format!("Node {} is not in the cluster file", pid)))?
+                .http_bind_address();
+            (cluster, vec![pid], http_address, true)
+        }
+        None => {
+            let mut cluster = ClusterConfig::local();
+            if !args.fresh {
+                membership::restore(&mut cluster);
+            }
+            let local_nodes = cluster.nodes.iter().map(|node| node.pid).collect();
+            (cluster, local_nodes, String::from(LOCAL_HTTP_ADDRESS), args.tcp)
+        }
+    };
+
+    let log_level = args.log_level.clone().or_else(|| cluster.log_level.clone());
+    logging::init(log_level.as_deref().unwrap_or(DEFAULT_LOG_LEVEL));
+
+    // nodes recover from their storage unless asked to start over
+    if args.fresh {
+        cleanup(&cluster, &local_nodes);
+    }
 
-    OP_SERVER_HANDLERS.lock().unwrap().extend(initialise_handlers());
+    let handlers = match joined {
+        Some(state) => membership::join(&cluster, local_nodes[0], state),
+        None => initialise_handlers(&cluster, &local_nodes, use_tcp),
+    };
+    OP_SERVER_HANDLERS.lock().unwrap().extend(handlers);
+    *CLUSTER.write().unwrap() = cluster;
+    *DEPLOYMENT.write().unwrap() = Deployment {
+        node_id: args.node_id,
+        use_tcp,
+    };
 
     HttpServer::new(move || {
         App::new()
+            .wrap_fn(logging::trace_request)
             .service(create)
             .service(get)
             .service(cas)
+            .service(remove)
+            .service(node_stats)
+            .service(cluster_stats)
+            .service(prometheus_metrics)
+            .service(add_node)
+            .service(remove_node)
+            .service(configuration)
+            .service(
+                web::resource("/snapshot")
+                    // imported snapshots are as large as the state, bounded like a message between nodes.
+                    // Their entry is checked against MAX_ENTRY_SIZE once it is encoded for the nodes
+                    .app_data(web::JsonConfig::default().limit(MAX_FRAME_SIZE as usize))
+                    .route(web::get().to(get_snapshot))
+                    .route(web::post().to(post_snapshot)),
+            )
     })
-        .bind(("127.0.0.1", 8000))?
+        .bind(http_address)?
         .run()
         .await
 }
 
-fn cleanup() -> () {
-    fs::remove_dir_all("storage1");
-    fs::remove_dir_all("storage2");
-    fs::remove_dir_all("storage3");
+fn invalid_input(error: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, error)
 }
 
-fn initialise_channels() -> (
-    HashMap<NodeId, mpsc::Sender<Message<KeyValue, KVSnapshot>>>,
-    HashMap<NodeId, mpsc::Receiver<Message<KeyValue, KVSnapshot>>>,
-) {
-    let mut sender_channels = HashMap::new();
-    let mut receiver_channels = HashMap::new();
-
-    for pid in SERVERS {
-        let (sender, receiver) = mpsc::channel(BUFFER_SIZE);
-        sender_channels.insert(pid, sender);
-        receiver_channels.insert(pid, receiver);
+fn cleanup(cluster: &ClusterConfig, local_nodes: &[NodeId]) {
+    for pid in local_nodes {
+        if let Some(node) = cluster.node(*pid) {
+            let _ = fs::remove_dir_all(&node.storage_dir);
+            // logs of the configurations later membership changes started
+            let path = Path::new(&node.storage_dir);
+            let prefix = format!("{}_config", path.file_name().unwrap_or_default().to_string_lossy());
+            let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            for entry in fs::read_dir(parent).into_iter().flatten().flatten() {
+                if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                    let _ = fs::remove_dir_all(entry.path());
+                }
+            }
+        }
     }
-    (sender_channels, receiver_channels)
 }
 
-fn initialise_handlers() -> HashMap<u64, (Arc<Mutex<OmniPaxosKV>>, JoinHandle<()>, OmniPaxosConfig)> {
-    // configuration with id 1 and the following cluster
-    let configuration_id = 1;
-
-    let (sender_storage, mut receiver_storage) = initialise_channels();
-
+/// Starts the `local_nodes` of `cluster` in this process.
+fn initialise_handlers(cluster: &ClusterConfig, local_nodes: &[NodeId], use_tcp: bool) -> HashMap<u64, NodeHandler> {
+    let mut channels = ChannelTransport::connect_all(local_nodes);
 
     let mut handlers = HashMap::new();
     // create the replicas in this cluster
-    for pid in SERVERS {
-        let peers = SERVERS.iter().filter(|&&p| p != pid).copied().collect();
-        let op_config = OmniPaxosConfig {
-            pid,
-            configuration_id,
-            peers,
-            ..Default::default()
-        };
-
-        // user-defined configuration for the persistent storage for each node
-        let persist_config = OmniPaxosServer::configure_persistent_storage(
-            String::from(PERSIST_PATH) + &*pid.to_string());
-        let omni_paxos: Arc<Mutex<OmniPaxosKV>> =
-            Arc::new(Mutex::new(op_config.clone().build(PersistentStorage::new(persist_config))));
-
-        let mut op_server = OmniPaxosServer {
-            omni_paxos: Arc::clone(&omni_paxos),
-            incoming: receiver_storage.remove(&pid).unwrap(),
-            outgoing: sender_storage.clone(),
-        };
-        let join_handle = RUNTIME.spawn({
-            async move {
-                op_server.run().await;
-            }
-        });
-        handlers.insert(pid, (omni_paxos, join_handle, op_config.clone()));
+    for node in cluster.nodes.iter().filter(|node| local_nodes.contains(&node.pid)) {
+        let storage_dir = cluster.storage_dir(node);
+        // checked before the statistics create the directory
+        let recover = Path::new(&storage_dir).exists();
+        let stats = Arc::new(
+            NodeStats::open(node.storage_dir.clone() + "/stats").expect("Failed to open the node's statistics"),
+        );
+        let transport = if use_tcp { None } else { channels.remove(&node.pid) };
+        // a configuration started by a membership change continues from the state it started from
+        let kv_store = membership::started().map(|state| state.kv_store()).unwrap_or_else(KVStore::new);
+        let handler = start_node(node, cluster, storage_dir, recover, kv_store, stats, transport);
+        handlers.insert(node.pid, handler);
     }
-    (handlers)
+    handlers
 }
 
+/// Starts `node` of `cluster` with the log kept in `storage_dir`, applying it on top of
+/// `kv_store`. With `recover` the log already in `storage_dir` is reopened and its decided
+/// entries applied before the node rejoins its peers. The node talks to its peers over
+/// `channels`, or over TCP without them.
+fn start_node(
+    node: &NodeConfig,
+    cluster: &ClusterConfig,
+    storage_dir: String,
+    recover: bool,
+    mut kv_store: KVStore,
+    stats: Arc<NodeStats>,
+    channels: Option<ChannelTransport>,
+) -> NodeHandler {
+    let pid = node.pid;
+    let op_config = OmniPaxosConfig {
+        pid,
+        configuration_id: cluster.configuration_id,
+        peers: cluster.peers(pid),
+        ..Default::default()
+    };
 
-fn recovery(pid: u64) -> (u64, (Arc<Mutex<OmniPaxosKV>>, JoinHandle<()>, OmniPaxosConfig)) {
-    // Configuration from previous storage
-    let handlers = OP_SERVER_HANDLERS.lock().unwrap();
-
-    std::thread::sleep(WAIT_LEADER_TIMEOUT * 5);
-
-    let follower = SERVERS.iter().find(|&&p| p != pid).unwrap();
-    let (follower_server, _, _) = handlers.get(follower).unwrap();
-    println!("---------------- Searching for a leader pid {:?}", follower);
-    let leader = follower_server
-        .lock()
-        .unwrap()
-        .get_current_leader()
-        .expect("Failed to get leader");
-    println!("Old leader: {}, asked this server: {}", leader, follower);
-
-    // Re-create storage with previous state, then create `OmniPaxos`
-    let (recovered_paxos, old_join, config)
-        = handlers.get(&pid).unwrap();
-
-    old_join.abort();
+    // user-defined configuration for the persistent storage for each node
+    let persist_config = configure_persistent_storage(storage_dir.clone());
+    let mut compaction = Compaction::new(pid, cluster.peers(pid), cluster.compaction.clone(), Arc::clone(&stats), &storage_dir);
+    let durable = (cluster.state_machine == StateMachine::Sled).then(|| {
+        DurableState::open(storage_dir.clone() + "/state").expect("Failed to open the node's state machine")
+    });
+    let omni_paxos: Arc<Mutex<OmniPaxosKV>> = if recover {
+        let mut omni_paxos = op_config.clone().build(PersistentStorage::open(persist_config));
+        omni_paxos.fail_recovery();
+        metrics::FAIL_RECOVERIES.with_label_values(&[&pid.to_string()]).inc();
+        // the sled state machine and the snapshot chain each cover a prefix of the log, at
+        // least the trimmed one, the node continues from the longer one
+        let stored = durable.as_ref().and_then(|durable| {
+            durable.load().unwrap_or_else(|err| {
+                warn!(pid, error = %err, "failed to load the state machine");
+                None
+            })
+        });
+        let stored_idx = stored.as_ref().map(|stored| stored.decided_idx);
+        if let Some(mut stored) = stored {
+            stored.start = kv_store.start.take();
+            kv_store = stored;
+        }
+        if let Some((idx, snapshot)) = compaction.restore() {
+            if idx > kv_store.log_idx() {
+                kv_store.install_log(idx, snapshot);
+            }
+        }
+        compaction.catch_up(kv_store.log_idx(), |range| omni_paxos.read_entries(range));
+        if stored_idx != Some(kv_store.decided_idx) {
+            kv_store.attach(durable);
+        } else {
+            kv_store.durable = durable;
+        }
+        if let Some(entries) = omni_paxos.read_decided_suffix(kv_store.log_idx()) {
+            match kv_store.apply_entries(&entries) {
+                Ok(_) => compaction.record(&entries),
+                Err(missing) => error!(
+                    pid,
+                    decided_idx = missing.decided_idx,
+                    trimmed_idx = missing.trimmed_idx,
+                    "the log was trimmed past the stored state"
+                ),
+            }
+        }
+        info!(pid, stored_idx, decided_idx = kv_store.decided_idx, "recovered from storage");
+        Arc::new(Mutex::new(omni_paxos))
+    } else {
+        kv_store.attach(durable);
+        Arc::new(Mutex::new(op_config.build(PersistentStorage::new(persist_config))))
+    };
+    let (applied_sender, applied_idx) = watch::channel(kv_store.decided_idx);
+    let base_idx = kv_store.base_idx();
+    let kv_store = Arc::new(Mutex::new(kv_store));
+    let proposals: Proposals = Arc::new(Mutex::new(HashMap::new()));
+    let handover = Handover::new(pid, cluster.peers(pid), cluster.handover.clone(), Arc::clone(&stats));
 
-    println!("---------------- Recovering pid {:?}", pid);
+    let join_handle = match channels {
+        Some(transport) => {
+            spawn_server(pid, transport, &omni_paxos, &kv_store, &proposals, &stats, applied_sender, handover, compaction)
+        }
+        None => {
+            let transport = TcpTransport::start(node, cluster, &omni_paxos);
+            spawn_server(pid, transport, &omni_paxos, &kv_store, &proposals, &stats, applied_sender, handover, compaction)
+        }
+    };
+    NodeHandler {
+        omni_paxos,
+        kv_store,
+        proposals,
+        applied_idx,
+        base_idx,
+        stats,
+        join_handle,
+    }
+}
 
-    let (sender_channels, mut receiver_channels) = initialise_channels();
-    let peers: Vec<u64> = SERVERS.iter().filter(|&&p| p != pid).copied().collect();
-    recovered_paxos.lock().unwrap().fail_recovery();
+#[allow(clippy::too_many_arguments)]
+fn spawn_server<T: Transport>(
+    pid: NodeId,
+    transport: T,
+    omni_paxos: &Arc<Mutex<OmniPaxosKV>>,
+    kv_store: &Arc<Mutex<KVStore>>,
+    proposals: &Proposals,
+    stats: &Arc<NodeStats>,
+    applied_idx: watch::Sender<u64>,
+    handover: Handover,
+    compaction: Compaction,
+) -> JoinHandle<()> {
     let mut op_server = OmniPaxosServer {
-        omni_paxos: Arc::clone(&recovered_paxos),
-        incoming: receiver_channels.remove(&pid).unwrap(),
-        outgoing: sender_channels.clone(),
+        pid,
+        omni_paxos: Arc::clone(omni_paxos),
+        transport,
+        kv_store: Arc::clone(kv_store),
+        proposals: Arc::clone(proposals),
+        stats: Arc::clone(stats),
+        applied_idx,
+        handover,
+        compaction,
+        leader: None,
+        missing_prefix: None,
     };
-    for peer in peers {
-        recovered_paxos.lock().unwrap().reconnected(peer);
-    }
-    let join_handle = RUNTIME.spawn({
+    RUNTIME.spawn({
         async move {
             op_server.run().await;
         }
-    });
-    std::thread::sleep(WAIT_LEADER_TIMEOUT * 2);
-    println!("---------------- Recovered pid {:?}", pid);
-
-    // Check leaders
-    let follower = SERVERS.iter().find(|&&p| p != pid).unwrap();
-    let (follower_server, _, _) = handlers.get(follower).unwrap();
-    println!("---------------- Searching for a leader pid {:?}", follower);
-    let leader = follower_server
-        .lock()
-        .unwrap()
-        .get_current_leader()
-        .expect("Failed to get leader");
-    println!("Elected new leader: {}, asked this server: {}", leader, follower);
-
-    (pid, (recovered_paxos.clone(), join_handle, config.clone()))
-}
\ No newline at end of file
+    })
+}
+
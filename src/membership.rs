@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use lazy_static::lazy_static;
+use omnipaxos_core::omni_paxos::ReconfigurationRequest;
+use omnipaxos_core::storage::StopSign;
+use omnipaxos_core::util::NodeId;
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::config::{ClusterConfig, NodeConfig};
+use crate::forward::CLIENT;
+use crate::kv::KVSnapshot;
+use crate::network::ChannelTransport;
+use crate::nodes::{KVStore, NodeHandler};
+use crate::stats::NodeStats;
+use crate::storage::{current_leader, StorageError};
+use crate::util::APPLY_PERIOD;
+use crate::{start_node, CLUSTER, DEPLOYMENT, OP_SERVER_HANDLERS};
+
+/// The state machine a configuration started from, which a node added to it starts from too.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConfigurationState {
+    pub configuration_id: u32,
+    pub nodes: Vec<NodeConfig>,
+    pub snapshot: KVSnapshot,
+}
+
+impl ConfigurationState {
+    /// The store every node of the configuration starts from, with the snapshot installed
+    /// before the configuration's empty log.
+    pub(crate) fn kv_store(&self) -> KVStore {
+        // the new log counts from 0, its indices continue from the stop-sign's
+        let mut kv_store = KVStore::new();
+        kv_store.install(self.snapshot.len, self.snapshot.clone());
+        kv_store.start = Some(Arc::new(self.snapshot.clone()));
+        kv_store
+    }
+}
+
+lazy_static! {
+    // the state the latest configuration started from, unset for the initial one
+    static ref STARTED: Mutex<Option<ConfigurationState>> = Mutex::new(None);
+}
+
+/// Proposes a stop-sign that moves the cluster to `nodes` and waits until it is decided.
+/// Returns the id of the new configuration.
+pub async fn reconfigure(nodes: Vec<NodeConfig>, deadline: Instant) -> Result<u32, StorageError> {
+    let pids = nodes.iter().map(|node| node.pid).collect();
+    // the nodes' addresses travel with the stop-sign so every node can reach the new ones
+    let metadata = serde_json::to_vec(&nodes).ok();
+    let omni_paxos = {
+        let handler = OP_SERVER_HANDLERS.lock().unwrap();
+        let leader_id = current_leader(&handler).ok_or(StorageError::Unavailable)?;
+        let leader = handler.get(&leader_id).ok_or(StorageError::Unavailable)?;
+        let proposed = leader
+            .omni_paxos
+            .lock()
+            .unwrap()
+            .reconfigure(ReconfigurationRequest::with(pids, metadata));
+        if let Err(err) = proposed {
+            warn!(leader_id, error = ?err, "reconfiguration was rejected");
+            return Err(StorageError::Unavailable);
+        }
+        Arc::clone(&leader.omni_paxos)
+    };
+    loop {
+        if let Some(stop_sign) = omni_paxos.lock().unwrap().is_reconfigured() {
+            return Ok(stop_sign.config_id);
+        }
+        if Instant::now() >= deadline {
+            return Err(StorageError::Timeout);
+        }
+        time::sleep(APPLY_PERIOD).await;
+    }
+}
+
+/// The state the latest configuration started from.
+pub fn started() -> Option<ConfigurationState> {
+    STARTED.lock().unwrap().clone()
+}
+
+/// Moves `cluster` to the latest configuration any of its nodes stored before a restart, so
+/// they reopen that configuration's log instead of the one its stop-sign ended.
+pub fn restore(cluster: &mut ClusterConfig) {
+    let latest = cluster
+        .nodes
+        .iter()
+        .filter_map(|node| serde_json::from_slice::<ConfigurationState>(&fs::read(state_path(node)).ok()?).ok())
+        .max_by_key(|state| state.configuration_id);
+    if let Some(state) = latest.filter(|state| state.configuration_id > cluster.configuration_id) {
+        cluster.configuration_id = state.configuration_id;
+        cluster.nodes = state.nodes.clone();
+        *STARTED.lock().unwrap() = Some(state);
+    }
+}
+
+/// The latest configuration a node took part in, kept next to the log of the initial one.
+fn state_path(node: &NodeConfig) -> PathBuf {
+    Path::new(&node.storage_dir).join("configuration.json")
+}
+
+/// Stores `state` in the storage directory of each of `nodes`, replacing the previous one at once.
+fn persist(state: &ConfigurationState, nodes: &[&NodeConfig]) {
+    let json = match serde_json::to_vec(state) {
+        Ok(json) => json,
+        Err(err) => {
+            warn!(error = %err, "failed to serialize the configuration");
+            return;
+        }
+    };
+    for node in nodes {
+        let path = state_path(node);
+        let tmp = path.with_extension("json.tmp");
+        let written = fs::create_dir_all(&node.storage_dir)
+            .and_then(|_| fs::write(&tmp, &json))
+            .and_then(|_| fs::rename(&tmp, &path));
+        if let Err(err) = written {
+            warn!(pid = node.pid, error = %err, "failed to store the configuration");
+        }
+    }
+}
+
+/// Replaces the nodes this process runs in the configuration `stop_sign` ended with their
+/// instances in the next one, each with fresh storage. `kv_store` is the state machine of the
+/// node that applied the stop-sign, every node of the next configuration starts from it.
+/// The new configuration is stored with every node this process ran in either one.
+pub fn start_configuration(stop_sign: StopSign, kv_store: &KVStore) {
+    let mut started = STARTED.lock().unwrap();
+    let (cluster, previous_nodes) = {
+        let mut cluster = CLUSTER.write().unwrap();
+        // another node of this process already started it
+        if stop_sign.config_id <= cluster.configuration_id {
+            return;
+        }
+        let nodes = stop_sign
+            .metadata
+            .as_ref()
+            .and_then(|metadata| serde_json::from_slice::<Vec<NodeConfig>>(metadata).ok())
+            .unwrap_or_else(|| {
+                cluster.nodes.iter().filter(|node| stop_sign.nodes.contains(&node.pid)).cloned().collect()
+            });
+        let previous_nodes = std::mem::replace(&mut cluster.nodes, nodes);
+        cluster.configuration_id = stop_sign.config_id;
+        (cluster.clone(), previous_nodes)
+    };
+    let state = ConfigurationState {
+        configuration_id: cluster.configuration_id,
+        nodes: cluster.nodes.clone(),
+        snapshot: KVSnapshot::of(&kv_store.key_value, &kv_store.sessions, kv_store.decided_idx),
+    };
+
+    let deployment = *DEPLOYMENT.read().unwrap();
+    let local_nodes: Vec<NodeId> = cluster
+        .nodes
+        .iter()
+        .map(|node| node.pid)
+        .filter(|pid| deployment.node_id.is_none() || deployment.node_id == Some(*pid))
+        .collect();
+    let mut handler = OP_SERVER_HANDLERS.lock().unwrap();
+    let previous: HashMap<NodeId, NodeHandler> = handler.drain().collect();
+    for node in previous.values() {
+        node.join_handle.abort();
+    }
+    let stored_with: Vec<&NodeConfig> = previous_nodes
+        .iter()
+        .filter(|node| previous.contains_key(&node.pid))
+        .chain(cluster.nodes.iter().filter(|node| local_nodes.contains(&node.pid) && !previous.contains_key(&node.pid)))
+        .collect();
+    persist(&state, &stored_with);
+    let mut channels = ChannelTransport::connect_all(&local_nodes);
+    for node in cluster.nodes.iter().filter(|node| local_nodes.contains(&node.pid)) {
+        let stats = match previous.get(&node.pid) {
+            Some(previous) => Arc::clone(&previous.stats),
+            None => open_stats(node),
+        };
+        let storage_dir = cluster.storage_dir(node);
+        let _ = fs::remove_dir_all(&storage_dir);
+        let transport = if deployment.use_tcp { None } else { channels.remove(&node.pid) };
+        let started_node = start_node(node, &cluster, storage_dir, false, state.kv_store(), stats, transport);
+        handler.insert(node.pid, started_node);
+    }
+    info!(
+        configuration_id = cluster.configuration_id,
+        nodes = ?stop_sign.nodes,
+        local_nodes = ?local_nodes,
+        "started the next configuration"
+    );
+    *started = Some(state);
+}
+
+/// Fetches the state of the current configuration from the cluster member at `address`.
+pub async fn fetch_state(address: &str) -> Result<ConfigurationState, String> {
+    let response = CLIENT
+        .get(format!("http://{}/admin/configuration", address))
+        .send()
+        .await
+        .map_err(|e| format!("Could not reach {}: {}", address, e))?;
+    if !response.status().is_success() {
+        return Err(format!("{} has no configuration to join: {}", address, response.status()));
+    }
+    response.json().await.map_err(|e| format!("Invalid configuration from {}: {}", address, e))
+}
+
+/// Starts node `pid` of the configuration described by `state` in `cluster`.
+pub fn join(cluster: &ClusterConfig, pid: NodeId, state: ConfigurationState) -> HashMap<NodeId, NodeHandler> {
+    let mut handlers = HashMap::new();
+    if let Some(node) = cluster.node(pid) {
+        persist(&state, &[node]);
+        let storage_dir = cluster.storage_dir(node);
+        let _ = fs::remove_dir_all(&storage_dir);
+        let handler = start_node(node, cluster, storage_dir, false, state.kv_store(), open_stats(node), None);
+        handlers.insert(pid, handler);
+    }
+    info!(pid, configuration_id = state.configuration_id, "joined the cluster");
+    *STARTED.lock().unwrap() = Some(state);
+    handlers
+}
+
+fn open_stats(node: &NodeConfig) -> Arc<NodeStats> {
+    Arc::new(NodeStats::open(node.storage_dir.clone() + "/stats").expect("Failed to open the node's statistics"))
+}
+
+#[cfg(test)]
+mod tests {
+    use omnipaxos_core::storage::Snapshot;
+
+    use super::*;
+
+    #[test]
+    fn restart_restores_the_stored_configuration() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cluster = ClusterConfig::local();
+        for node in cluster.nodes.iter_mut() {
+            node.storage_dir = dir.path().join(format!("node{}", node.pid)).to_string_lossy().into_owned();
+        }
+        let mut added = cluster.nodes[0].clone();
+        added.pid = 4;
+        added.storage_dir = dir.path().join("node4").to_string_lossy().into_owned();
+        let state = ConfigurationState {
+            configuration_id: 2,
+            nodes: vec![cluster.nodes[1].clone(), cluster.nodes[2].clone(), added],
+            snapshot: KVSnapshot::create(&[]),
+        };
+        // only the removed node 1 stored it, as the process running it did
+        persist(&state, &[&cluster.nodes[0]]);
+
+        let mut restarted = cluster.clone();
+        restore(&mut restarted);
+        assert_eq!(restarted.configuration_id, 2);
+        assert_eq!(restarted.nodes.iter().map(|node| node.pid).collect::<Vec<_>>(), vec![2, 3, 4]);
+        assert_eq!(started().unwrap().configuration_id, 2);
+        assert_eq!(restarted.storage_dir(&restarted.nodes[2]), format!("{}_config2", restarted.nodes[2].storage_dir));
+    }
+}
@@ -0,0 +1,37 @@
+use lazy_static::lazy_static;
+use prometheus::core::Collector;
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry};
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new();
+    /// Seconds from appending a write to the leader until the leader applied it.
+    pub static ref WRITE_LATENCY: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("kv_write_latency_seconds", "Time from appending a write until it is decided and applied"),
+        &["node"],
+    ).unwrap());
+    pub static ref READ_LATENCY: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("kv_read_latency_seconds", "Time to serve a read by consistency mode"),
+        &["node", "consistency"],
+    ).unwrap());
+    pub static ref CAS_TOTAL: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("kv_cas_total", "Applied CAS commands by result"),
+        &["node", "result"],
+    ).unwrap());
+    pub static ref LEADER_CHANGES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("kv_leader_changes_total", "Leader changes observed by a node"),
+        &["node"],
+    ).unwrap());
+    pub static ref OUTGOING_QUEUE_DEPTH: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new("kv_outgoing_queue_depth", "Messages queued for a peer"),
+        &["node", "peer"],
+    ).unwrap());
+    pub static ref FAIL_RECOVERIES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("kv_fail_recoveries_total", "Times a node recovered from a crash with fail_recovery"),
+        &["node"],
+    ).unwrap());
+}
+
+fn register<T: Collector + Clone + 'static>(metric: T) -> T {
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    metric
+}
@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use omnipaxos_core::{messages::Message, util::NodeId};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, error, info, warn};
+
+use crate::config::{ClusterConfig, NodeConfig};
+use crate::kv::{KVEntry, KVSnapshot};
+use crate::util::{BUFFER_SIZE, MAX_FRAME_SIZE, RECONNECT_BACKOFF_MAX, RECONNECT_BACKOFF_MIN};
+use crate::{OmniPaxosKV, RUNTIME};
+
+pub type KVMessage = Message<KVEntry, KVSnapshot>;
+
+/// Everything nodes send each other.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum NodeMessage {
+    OmniPaxos(KVMessage),
+    /// The requests the sender handled within the handover window and its leader priority.
+    Requests { from: NodeId, to: NodeId, requests: u64, priority: u64 },
+    /// The index up to which the sender has stored its snapshot chain, gossiped for trimming.
+    Checkpoint { from: NodeId, to: NodeId, idx: u64 },
+}
+
+impl NodeMessage {
+    pub fn receiver(&self) -> NodeId {
+        match self {
+            NodeMessage::OmniPaxos(msg) => msg.get_receiver(),
+            NodeMessage::Requests { to, .. } | NodeMessage::Checkpoint { to, .. } => *to,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransportError {
+    /// There is no link to the receiver.
+    UnknownReceiver(NodeId),
+    /// The link to the receiver is gone, the message was dropped.
+    Disconnected(NodeId),
+}
+
+/// How an `OmniPaxosServer` exchanges messages with its peers.
+pub trait Transport: Send + 'static {
+    /// Hands `msg` over for delivery to its receiver. Delivery is best effort, OmniPaxos
+    /// resends what is lost once the peer is `reconnected`.
+    fn send(&mut self, msg: NodeMessage) -> impl Future<Output = Result<(), TransportError>> + Send;
+
+    /// The next message from any peer, `None` once no more can arrive.
+    fn recv(&mut self) -> impl Future<Output = Option<NodeMessage>> + Send;
+
+    /// How many messages are queued for each peer and not yet handed to the network.
+    fn queue_depths(&self) -> Vec<(NodeId, usize)>;
+}
+
+fn queue_depths(outgoing: &HashMap<NodeId, mpsc::Sender<NodeMessage>>) -> Vec<(NodeId, usize)> {
+    outgoing
+        .iter()
+        .map(|(pid, sender)| (*pid, sender.max_capacity() - sender.capacity()))
+        .collect()
+}
+
+/// Channels between nodes running in the same process.
+pub struct ChannelTransport {
+    pub incoming: mpsc::Receiver<NodeMessage>,
+    pub outgoing: HashMap<NodeId, mpsc::Sender<NodeMessage>>,
+}
+
+impl ChannelTransport {
+    /// Connects every node in `pids` to every other one.
+    pub fn connect_all(pids: &[NodeId]) -> HashMap<NodeId, ChannelTransport> {
+        let mut sender_channels = HashMap::new();
+        let mut receiver_channels = HashMap::new();
+        for &pid in pids {
+            let (sender, receiver) = mpsc::channel(BUFFER_SIZE);
+            sender_channels.insert(pid, sender);
+            receiver_channels.insert(pid, receiver);
+        }
+        receiver_channels
+            .into_iter()
+            .map(|(pid, incoming)| (pid, ChannelTransport { incoming, outgoing: sender_channels.clone() }))
+            .collect()
+    }
+}
+
+impl Transport for ChannelTransport {
+    async fn send(&mut self, msg: NodeMessage) -> Result<(), TransportError> {
+        let receiver = msg.receiver();
+        let channel = self.outgoing.get(&receiver).ok_or(TransportError::UnknownReceiver(receiver))?;
+        channel.send(msg).await.map_err(|_| TransportError::Disconnected(receiver))
+    }
+
+    async fn recv(&mut self) -> Option<NodeMessage> {
+        self.incoming.recv().await
+    }
+
+    fn queue_depths(&self) -> Vec<(NodeId, usize)> {
+        queue_depths(&self.outgoing)
+    }
+}
+
+/// Length-prefixed frames over a TCP connection to each peer.
+pub struct TcpTransport {
+    incoming: mpsc::Receiver<NodeMessage>,
+    outgoing: HashMap<NodeId, mpsc::Sender<NodeMessage>>,
+    // the listener and connection tasks, stopped with the transport to free the peer address
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl TcpTransport {
+    /// Listens on `node`'s peer bind address and starts a connection task for every other node in `cluster`.
+    pub fn start(node: &NodeConfig, cluster: &ClusterConfig, omni_paxos: &Arc<Mutex<OmniPaxosKV>>) -> Self {
+        let (incoming_sender, incoming) = mpsc::channel(BUFFER_SIZE);
+        let (pid, bind_address) = (node.pid, node.peer_bind_address());
+        let mut tasks = vec![RUNTIME.spawn(async move {
+            if let Err(err) = listen(&bind_address, incoming_sender).await {
+                error!(pid, %bind_address, error = %err, "stopped listening for peers");
+            }
+        })];
+        let mut outgoing = HashMap::new();
+        for peer in cluster.nodes.iter().filter(|peer| peer.pid != pid) {
+            let (sender, receiver) = mpsc::channel(BUFFER_SIZE);
+            tasks.push(RUNTIME.spawn(connect(peer.pid, peer.peer_address.clone(), receiver, Arc::clone(omni_paxos))));
+            outgoing.insert(peer.pid, sender);
+        }
+        TcpTransport { incoming, outgoing, tasks }
+    }
+}
+
+impl Drop for TcpTransport {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Transport for TcpTransport {
+    async fn send(&mut self, msg: NodeMessage) -> Result<(), TransportError> {
+        let receiver = msg.receiver();
+        let channel = self.outgoing.get(&receiver).ok_or(TransportError::UnknownReceiver(receiver))?;
+        // never wait on a slow link, the connection task drops what it cannot deliver anyway
+        channel.try_send(msg).map_err(|_| TransportError::Disconnected(receiver))
+    }
+
+    async fn recv(&mut self) -> Option<NodeMessage> {
+        self.incoming.recv().await
+    }
+
+    fn queue_depths(&self) -> Vec<(NodeId, usize)> {
+        queue_depths(&self.outgoing)
+    }
+}
+
+/// Writes `msg` as a big-endian u32 length followed by its bincode encoding.
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, msg: &NodeMessage) -> io::Result<()> {
+    let bytes = bincode::serialize(msg).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_bytes(writer, &bytes).await
+}
+
+/// Writes `bytes` as one frame. A frame larger than `MAX_FRAME_SIZE`, which the peer would
+/// refuse, fails with `InvalidInput` before anything is written.
+async fn write_bytes<W: AsyncWrite + Unpin>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    let len = match u32::try_from(bytes.len()) {
+        Ok(len) if len <= MAX_FRAME_SIZE => len,
+        _ => {
+            let error = format!("Frame of {} bytes is too large", bytes.len());
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, error));
+        }
+    };
+    writer.write_u32(len).await?;
+    writer.write_all(bytes).await?;
+    writer.flush().await
+}
+
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<NodeMessage> {
+    let len = reader.read_u32().await?;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Frame of {} bytes is too large", len)));
+    }
+    let mut bytes = vec![0; len as usize];
+    reader.read_exact(&mut bytes).await?;
+    bincode::deserialize(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Accepts connections from peers on `addr` and forwards the messages they send into `incoming`.
+/// Binding is retried for a while since the node's instance in the previous configuration may
+/// not have released the address yet.
+async fn listen(addr: &str, incoming: mpsc::Sender<NodeMessage>) -> io::Result<()> {
+    let mut backoff = RECONNECT_BACKOFF_MIN;
+    let listener = loop {
+        match TcpListener::bind(addr).await {
+            Ok(listener) => break listener,
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse && backoff < RECONNECT_BACKOFF_MAX => {
+                time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(err) => return Err(err),
+        }
+    };
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let _ = stream.set_nodelay(true);
+        let incoming = incoming.clone();
+        tokio::spawn(async move {
+            let mut stream = stream;
+            loop {
+                match read_frame(&mut stream).await {
+                    Ok(msg) => {
+                        if incoming.send(msg).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        debug!(%peer_addr, error = %err, "connection from peer closed");
+                        return;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Sends the messages queued in `outgoing` to `peer` at `addr`, a `host:port` resolved on every
+/// attempt. The connection is re-established with exponential backoff whenever it fails, messages
+/// queued meanwhile are dropped and OmniPaxos is told the peer `reconnected` once the link is
+/// back so it resends what was lost.
+async fn connect(
+    peer: NodeId,
+    addr: String,
+    mut outgoing: mpsc::Receiver<NodeMessage>,
+    omni_paxos: Arc<Mutex<OmniPaxosKV>>,
+) {
+    let mut backoff = RECONNECT_BACKOFF_MIN;
+    let mut link_failed = false;
+    loop {
+        let mut stream = match TcpStream::connect(addr.as_str()).await {
+            Ok(stream) => stream,
+            Err(_) => {
+                link_failed = true;
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                // the peer cannot receive them, OmniPaxos resends after `reconnected`
+                while outgoing.try_recv().is_ok() {}
+                continue;
+            }
+        };
+        let _ = stream.set_nodelay(true);
+        backoff = RECONNECT_BACKOFF_MIN;
+        if link_failed {
+            info!(peer, %addr, "reconnected to peer");
+            omni_paxos.lock().unwrap().reconnected(peer);
+        }
+        loop {
+            let msg = match outgoing.recv().await {
+                Some(msg) => msg,
+                None => return,
+            };
+            // a message too large to send fails the link as well, the peer must not miss it
+            // while later ones arrive, and OmniPaxos only resends it after `reconnected`
+            if let Err(err) = write_frame(&mut stream, &msg).await {
+                if err.kind() == io::ErrorKind::InvalidInput {
+                    error!(peer, %addr, error = %err, "message too large to send, reconnecting");
+                } else {
+                    warn!(peer, %addr, error = %err, "connection to peer failed");
+                }
+                link_failed = true;
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, &NodeMessage::Checkpoint { from: 1, to: 2, idx: 7 }).await.unwrap();
+        write_frame(&mut bytes, &NodeMessage::Requests { from: 2, to: 1, requests: 5, priority: 3 }).await.unwrap();
+
+        let mut reader = bytes.as_slice();
+        let first = read_frame(&mut reader).await.unwrap();
+        assert!(matches!(first, NodeMessage::Checkpoint { from: 1, to: 2, idx: 7 }));
+        let second = read_frame(&mut reader).await.unwrap();
+        assert!(matches!(second, NodeMessage::Requests { from: 2, to: 1, requests: 5, priority: 3 }));
+        let end = read_frame(&mut reader).await.unwrap_err();
+        assert_eq!(end.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_refused() {
+        let mut bytes = Vec::new();
+        let err = write_bytes(&mut bytes, &vec![0; MAX_FRAME_SIZE as usize + 1]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(bytes.is_empty());
+
+        let header = (MAX_FRAME_SIZE + 1).to_be_bytes();
+        let err = read_frame(&mut header.as_slice()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
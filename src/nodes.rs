@@ -1,64 +1,186 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
-use lazy_static::lazy_static;
-
-lazy_static! {
-    // mimic multiple web server replicas
-    pub static ref STORAGE_REPLICAS: Vec<Arc<Mutex<KVStore>>> = {
-        let mut set = Vec::new();
-        set.insert(0, Arc::new(Mutex::new(
-            KVStore {
-                        key_value: HashMap::new(),
-                        decided_idx: 0,
-                    })
-        ));
-         set.insert(0, Arc::new(Mutex::new(
-            KVStore {
-                        key_value: HashMap::new(),
-                        decided_idx: 0,
-                    })
-        ));
-         set.insert(0, Arc::new(Mutex::new(
-            KVStore {
-                        key_value: HashMap::new(),
-                        decided_idx: 0,
-                    })
-        ));
-        set
-    };
-
-    pub static ref TEST_STORAGE_REPLICAS: Vec<Arc<Mutex<KVStore>>> = {
-        let mut set = Vec::new();
-         set.insert(0, Arc::new(Mutex::new(
-            KVStore {
-                        key_value: HashMap::new(),
-                        decided_idx: 0,
-                    })
-        ));
-         set.insert(0, Arc::new(Mutex::new(
-            KVStore {
-                        key_value: HashMap::new(),
-                        decided_idx: 0,
-                    })
-        ));
-        set.insert(0, STORAGE_REPLICAS.get(0).unwrap().clone());
-        set
-    };
+use omnipaxos_core::storage::Snapshot;
+use omnipaxos_core::util::LogEntry;
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use crate::durable_state::DurableState;
+use crate::kv::{ClientSession, KVEntry, KVOutcome, KVSnapshot};
+use crate::stats::NodeStats;
+use crate::OmniPaxosKV;
+
+/// Proposals waiting to be applied, completed with their decided index and outcome.
+pub type Proposals = Arc<Mutex<HashMap<u64, oneshot::Sender<(u64, KVOutcome)>>>>;
+
+/// Counts a client request for every node running in this process. A request forwarded by
+/// another node was already counted there. Handover compares these counts, so it only moves
+/// leadership when every process runs a single node.
+pub fn count_request(handler: &HashMap<u64, NodeHandler>) {
+    for node in handler.values() {
+        NodeStats::incr(&node.stats.requests);
+    }
+}
+
+/// An OmniPaxos replica together with the state machine it applies its decided log into.
+pub struct NodeHandler {
+    pub omni_paxos: Arc<Mutex<OmniPaxosKV>>,
+    pub kv_store: Arc<Mutex<KVStore>>,
+    pub proposals: Proposals,
+    /// The decided index the node's apply loop has reached.
+    pub applied_idx: watch::Receiver<u64>,
+    /// The decided index the configuration's log starts after, which OmniPaxos' indices into
+    /// the log are offset by.
+    pub base_idx: u64,
+    pub stats: Arc<NodeStats>,
+    pub join_handle: JoinHandle<()>,
+}
+
+/// The decided suffix starts after a prefix that delta compaction trimmed before this node
+/// applied it. Only the other nodes' snapshot chains hold that prefix, so the node cannot
+/// catch up from its log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MissingPrefix {
+    pub decided_idx: u64,
+    pub trimmed_idx: u64,
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct KVStore {
     pub key_value: HashMap<String, u64>,
     pub decided_idx: u64,
+    /// Replicated deduplication table, the latest applied request per client.
+    pub sessions: HashMap<String, ClientSession>,
+    /// Where the state is written after every applied batch, if it is kept in sled.
+    #[serde(skip)]
+    pub durable: Option<DurableState>,
+    /// The state a configuration started by a membership change continues from. Its log counts
+    /// from 0 again, so its indices are offset by the state's `len` and its snapshots only cover
+    /// what follows the state.
+    #[serde(skip)]
+    pub start: Option<Arc<KVSnapshot>>,
 }
 
 impl KVStore {
-    pub(crate) fn get_storage(id: usize, is_failed: bool) -> Arc<Mutex<KVStore>> {
-        if is_failed {
-            TEST_STORAGE_REPLICAS.get(id).unwrap().clone()
-        } else {
-            STORAGE_REPLICAS.get(id).unwrap().clone()
+    pub(crate) fn new() -> Self {
+        KVStore {
+            key_value: HashMap::new(),
+            decided_idx: 0,
+            sessions: HashMap::new(),
+            durable: None,
+            start: None,
         }
     }
-}
\ No newline at end of file
+
+    /// The decided index this configuration's log starts after.
+    pub(crate) fn base_idx(&self) -> u64 {
+        self.start.as_ref().map_or(0, |start| start.len)
+    }
+
+    /// The index in this configuration's log up to which the store has applied it.
+    pub(crate) fn log_idx(&self) -> u64 {
+        self.decided_idx.saturating_sub(self.base_idx())
+    }
+
+    /// Writes the whole state to `durable`, which then receives every applied batch.
+    pub(crate) fn attach(&mut self, mut durable: Option<DurableState>) {
+        if let Some(durable) = &mut durable {
+            if let Err(err) = durable.replace(self) {
+                warn!(error = %err, "failed to store the state machine");
+            }
+        }
+        self.durable = durable;
+    }
+
+    /// Replaces the whole state with `snapshot` of the log up to `idx` at once, the entries
+    /// after `idx` are then applied on top of it.
+    pub(crate) fn install(&mut self, idx: u64, mut snapshot: KVSnapshot) {
+        snapshot.resolve();
+        // the sessions' indices are counted from the first entry the snapshot covers
+        let start = idx.saturating_sub(snapshot.len);
+        for session in snapshot.sessions.values_mut() {
+            session.decided_idx += start;
+        }
+        self.key_value = snapshot.snapshotted;
+        self.sessions = snapshot.sessions;
+        self.decided_idx = idx;
+    }
+
+    /// Replaces the whole state with `snapshot` of this configuration's log up to `log_idx`,
+    /// on top of the state the configuration started from.
+    pub(crate) fn install_log(&mut self, log_idx: u64, snapshot: KVSnapshot) {
+        let snapshot = match &self.start {
+            Some(start) => {
+                let mut state = KVSnapshot::clone(start);
+                state.merge(snapshot);
+                state
+            }
+            None => snapshot,
+        };
+        self.install(self.base_idx() + log_idx, snapshot);
+    }
+
+    /// Applies entries read from the decided suffix starting at `self.log_idx()` and
+    /// returns the proposal id, decided index and outcome of each applied entry. Applies nothing
+    /// if the entries start after a trimmed prefix.
+    pub(crate) fn apply_entries(
+        &mut self,
+        entries: &[LogEntry<KVEntry, KVSnapshot>],
+    ) -> Result<Vec<(u64, u64, KVOutcome)>, MissingPrefix> {
+        let trimmed_idx = entries.iter().find_map(|ent| match ent {
+            LogEntry::Trimmed(trimmed_idx) => Some(*trimmed_idx),
+            _ => None,
+        });
+        if let Some(trimmed_idx) = trimmed_idx {
+            return Err(MissingPrefix { decided_idx: self.decided_idx, trimmed_idx });
+        }
+        let mut applied = Vec::new();
+        for ent in entries {
+            match ent {
+                LogEntry::Decided(entry_decided) => {
+                    self.decided_idx += 1;
+                    let outcome = entry_decided.apply(self.decided_idx, &mut self.key_value, &mut self.sessions);
+                    debug!(proposal_id = entry_decided.proposal_id, decided_idx = self.decided_idx, ?outcome, "applied entry");
+                    applied.push((entry_decided.proposal_id, self.decided_idx, outcome));
+                }
+                // a replica behind the compacted prefix catches up from the snapshot of it
+                LogEntry::Snapshotted(kv_snapshotted) => {
+                    self.install_log(kv_snapshotted.trimmed_idx, kv_snapshotted.snapshot.clone());
+                    debug!(decided_idx = self.decided_idx, "installed snapshot");
+                }
+                // the stop-sign occupies a slot, the next configuration starts from this state
+                LogEntry::StopSign(_) => self.decided_idx += 1,
+                LogEntry::Undecided(_) | LogEntry::Trimmed(_) => {}
+            }
+        }
+        if let Some(mut durable) = self.durable.take() {
+            // the stored state stays at its index, the next batch then stores the whole state
+            if let Err(err) = durable.save(self, entries) {
+                warn!(decided_idx = self.decided_idx, error = %err, "failed to store the state machine");
+            }
+            self.durable = Some(durable);
+        }
+        Ok(applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(key: &str, value: u64) -> LogEntry<KVEntry, KVSnapshot> {
+        LogEntry::Decided(KVEntry::put(key, value))
+    }
+
+    #[test]
+    fn apply_entries_refuses_a_trimmed_prefix() {
+        let mut kv_store = KVStore::new();
+        kv_store.apply_entries(&[put("a", 1)]).unwrap();
+        let missing = kv_store.apply_entries(&[LogEntry::Trimmed(5), put("b", 2)]).unwrap_err();
+        assert_eq!(missing, MissingPrefix { decided_idx: 1, trimmed_idx: 5 });
+        assert_eq!(kv_store.decided_idx, 1);
+        assert!(!kv_store.key_value.contains_key("b"));
+    }
+}
@@ -1,71 +1,184 @@
-use std::{collections::HashMap, sync::{Arc, Mutex}};
+use std::sync::{Arc, Mutex};
 
 use commitlog::LogOptions;
-use omnipaxos_core::{messages::Message, util::NodeId};
 use omnipaxos_storage::persistent_storage::PersistentStorageConfig;
+use omnipaxos_core::util::NodeId;
 use sled::Config;
-use tokio::{sync::mpsc, time};
+use tokio::{sync::watch, time};
+use tracing::{error, warn};
 
-use crate::{OmniPaxosKV, recovery, RUNTIME, TO_RECOVER, util::{ELECTION_TIMEOUT, OUTGOING_MESSAGE_PERIOD}, WAIT_LEADER_TIMEOUT};
-use crate::kv::{KeyValue, KVSnapshot};
+use crate::{OmniPaxosKV, util::{APPLY_PERIOD, ELECTION_TIMEOUT, COMPACTION_PERIOD, GOSSIP_PERIOD, OUTGOING_MESSAGE_PERIOD, STATS_PERSIST_PERIOD}};
+use crate::compaction::Compaction;
+use crate::handover::Handover;
+use crate::membership;
+use crate::metrics;
+use crate::network::{NodeMessage, Transport};
+use crate::nodes::{KVStore, MissingPrefix, Proposals};
+use crate::stats::NodeStats;
 
-pub struct OmniPaxosServer {
+pub struct OmniPaxosServer<T: Transport> {
+    pub pid: NodeId,
     pub omni_paxos: Arc<Mutex<OmniPaxosKV>>,
-    pub incoming: mpsc::Receiver<Message<KeyValue, KVSnapshot>>,
-    pub outgoing: HashMap<NodeId, mpsc::Sender<Message<KeyValue, KVSnapshot>>>,
+    pub transport: T,
+    pub kv_store: Arc<Mutex<KVStore>>,
+    pub proposals: Proposals,
+    pub stats: Arc<NodeStats>,
+    pub applied_idx: watch::Sender<u64>,
+    pub handover: Handover,
+    pub compaction: Compaction,
+    /// The leader this node saw last.
+    pub leader: Option<NodeId>,
+    /// Set once the apply loop stopped at a prefix trimmed before this node applied it.
+    pub missing_prefix: Option<MissingPrefix>,
 }
 
-impl OmniPaxosServer {
+impl<T: Transport> OmniPaxosServer<T> {
+    fn update_metrics(&mut self) {
+        let node = self.pid.to_string();
+        for (peer, depth) in self.transport.queue_depths() {
+            if peer != self.pid {
+                metrics::OUTGOING_QUEUE_DEPTH
+                    .with_label_values(&[&node, &peer.to_string()])
+                    .set(depth as i64);
+            }
+        }
+        let leader = self.omni_paxos.lock().unwrap().get_current_leader();
+        if leader != self.leader {
+            if self.leader.is_some() {
+                metrics::LEADER_CHANGES.with_label_values(&[&node]).inc();
+            }
+            self.leader = leader;
+        }
+    }
+
     async fn send_outgoing_msgs(&mut self) {
         let messages = self.omni_paxos.lock().unwrap().outgoing_messages();
         for msg in messages {
-            // println!("Outgoing message: {:?}", msg);
             let receiver = msg.get_receiver();
             // send out_msg to receiver on network layer
-            let channel = self
-                .outgoing
-                .get_mut(&receiver)
-                .expect("No channel for receiver");
-            let response = channel.send(msg).await;
-            // println!("Response message: {:?}", response);
-            if response.is_err() {
-                println!("Here is error: {:?}, pid {}", response, receiver);
+            let response = self.transport.send(NodeMessage::OmniPaxos(msg)).await;
+            if response.is_ok() {
+                NodeStats::incr(&self.stats.messages_sent);
+            } else {
+                NodeStats::incr(&self.stats.send_errors);
+                warn!(receiver, error = ?response, "failed to send message");
                 self.omni_paxos.lock().unwrap().reconnected(receiver);
             }
-            // else {
-            //     let pr: i32 = rand::thread_rng().gen_range(1..100);
-            //     println!("Probability: {}", pr);
-            //     if pr > 90 {
-            //         let (k, v) = recovery(receiver);
-            //         OP_SERVER_HANDLERS.lock().unwrap().insert(k, v);
-            //     }
-            // }
+        }
+    }
+
+    /// Materializes the decided suffix that has not been applied yet into this node's `KVStore`
+    /// and completes the proposals waiting for the applied entries.
+    fn apply_decided(&mut self) {
+        let (applied, applied_idx, stop_sign) = {
+            let mut kv_store = self.kv_store.lock().unwrap();
+            let omni_paxos = self.omni_paxos.lock().unwrap();
+            if omni_paxos.get_decided_idx() <= kv_store.log_idx() {
+                return;
+            }
+            let applied = match omni_paxos.read_decided_suffix(kv_store.log_idx()) {
+                Some(committed_ents) => match kv_store.apply_entries(&committed_ents) {
+                    Ok(applied) => {
+                        self.compaction.record(&committed_ents);
+                        applied
+                    }
+                    Err(missing) => {
+                        if self.missing_prefix.replace(missing).is_none() {
+                            error!(
+                                pid = self.pid,
+                                decided_idx = missing.decided_idx,
+                                trimmed_idx = missing.trimmed_idx,
+                                "the log was trimmed past this node's state, it stops applying until it is removed and added again"
+                            );
+                        }
+                        return;
+                    }
+                },
+                None => return,
+            };
+            // a decided stop-sign is the last entry of this configuration's log
+            let stop_sign = omni_paxos.is_reconfigured().map(|stop_sign| (stop_sign, kv_store.clone()));
+            (applied, kv_store.decided_idx, stop_sign)
+        };
+        self.applied_idx.send_replace(applied_idx);
+        {
+            let mut proposals = self.proposals.lock().unwrap();
+            for (proposal_id, decided_idx, outcome) in applied {
+                if let Some(sender) = proposals.remove(&proposal_id) {
+                    // the proposer may have stopped waiting
+                    let _ = sender.send((decided_idx, outcome));
+                }
+            }
+        }
+        if let Some((stop_sign, kv_store)) = stop_sign {
+            membership::start_configuration(stop_sign, &kv_store);
+        }
+    }
+
+    /// Gossips this node's request count and raises its leader priority above every other
+    /// node's once it should take over leadership, which makes it win the next election.
+    async fn gossip_requests(&mut self) {
+        for msg in self.handover.gossip().into_iter().chain(self.compaction.gossip()) {
+            // a lost count is sent again next period
+            let _ = self.transport.send(msg).await;
+        }
+        let mut omni_paxos = self.omni_paxos.lock().unwrap();
+        if let Some(priority) = self.handover.check(omni_paxos.get_current_leader()) {
+            omni_paxos.set_priority(priority);
+        }
+    }
+
+    fn handle_incoming(&mut self, msg: NodeMessage) {
+        match msg {
+            NodeMessage::OmniPaxos(msg) => {
+                NodeStats::incr(&self.stats.messages_received);
+                self.omni_paxos.lock().unwrap().handle_incoming(msg)
+            }
+            NodeMessage::Requests { from, requests, priority, .. } => self.handover.receive(from, requests, priority),
+            NodeMessage::Checkpoint { from, idx, .. } => self.compaction.receive(from, idx),
         }
     }
 
     pub(crate) async fn run(&mut self) {
         let mut outgoing_interval = time::interval(OUTGOING_MESSAGE_PERIOD);
         let mut election_interval = time::interval(ELECTION_TIMEOUT);
+        let mut apply_interval = time::interval(APPLY_PERIOD);
+        let mut gossip_interval = time::interval(GOSSIP_PERIOD);
+        let mut stats_interval = time::interval(STATS_PERSIST_PERIOD);
+        let mut compaction_interval = time::interval(COMPACTION_PERIOD);
         loop {
             tokio::select! {
                 biased;
                 _ = election_interval.tick() => { self.omni_paxos.lock().unwrap().election_timeout(); },
-                _ = outgoing_interval.tick() => { self.send_outgoing_msgs().await; },
-                Some(in_msg) = self.incoming.recv() => { self.omni_paxos.lock().unwrap().handle_incoming(in_msg); },
+                _ = outgoing_interval.tick() => { self.send_outgoing_msgs().await; self.update_metrics(); },
+                _ = apply_interval.tick() => { self.apply_decided(); },
+                _ = gossip_interval.tick() => { self.gossip_requests().await; },
+                _ = compaction_interval.tick() => {
+                    // compaction counts in this configuration's log, the applied index continues
+                    // from earlier configurations
+                    let applied_idx = self.kv_store.lock().unwrap().log_idx();
+                    self.compaction.run(&mut self.omni_paxos.lock().unwrap(), applied_idx);
+                },
+                _ = stats_interval.tick() => {
+                    if let Err(err) = self.stats.persist() {
+                        warn!(pid = self.pid, error = %err, "failed to persist statistics");
+                    }
+                },
+                Some(in_msg) = self.transport.recv() => { self.handle_incoming(in_msg); },
                 else => { }
             }
         }
     }
+}
 
-    pub(crate) fn configure_persistent_storage(path: String) -> PersistentStorageConfig {
-        let log_opts = LogOptions::new(path.clone());
-        let mut sled_opts = Config::new();
-        sled_opts = Config::path(sled_opts, path.clone());
+pub(crate) fn configure_persistent_storage(path: String) -> PersistentStorageConfig {
+    let log_opts = LogOptions::new(path.clone());
+    let mut sled_opts = Config::new();
+    sled_opts = Config::path(sled_opts, path.clone());
 
-        // generate default configuration and set user-defined options
-        let persist_config = PersistentStorageConfig::with(
-            path.to_string(), log_opts, sled_opts);
+    // generate default configuration and set user-defined options
+    let persist_config = PersistentStorageConfig::with(
+        path.to_string(), log_opts, sled_opts);
 
-        persist_config
-    }
-}
\ No newline at end of file
+    persist_config
+}
@@ -0,0 +1,153 @@
+use std::io;
+
+use omnipaxos_core::storage::Snapshot;
+
+use crate::kv::KVSnapshot;
+
+const BASE_KEY: &str = "base";
+
+/// A node's snapshots on disk: a full base snapshot and the deltas taken after it, each
+/// holding only the keys changed since the previous one. Reading merges the chain in order,
+/// consolidating folds it into a new base.
+pub struct SnapshotChain {
+    db: sled::Db,
+    deltas: sled::Tree,
+}
+
+impl SnapshotChain {
+    pub fn open(path: String) -> sled::Result<Self> {
+        let db = sled::open(path)?;
+        let deltas = db.open_tree("deltas")?;
+        Ok(SnapshotChain { db, deltas })
+    }
+
+    /// Stores `delta` of the entries up to `idx` that were applied since the previous one.
+    pub fn append(&self, idx: u64, delta: &KVSnapshot) -> sled::Result<()> {
+        self.deltas.insert(idx.to_be_bytes(), encode(delta)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn deltas(&self) -> usize {
+        self.deltas.len()
+    }
+
+    /// The state up to the last stored index, the base merged with the deltas after it.
+    pub fn read(&self) -> sled::Result<Option<(u64, KVSnapshot)>> {
+        let (mut idx, mut snapshot) = match self.db.get(BASE_KEY)? {
+            Some(bytes) => decode::<(u64, KVSnapshot)>(&bytes)?,
+            None => (0, KVSnapshot::create(&[])),
+        };
+        let mut found = idx > 0;
+        // deltas up to the base were folded into it by a consolidation that stopped early
+        for delta in self.deltas.range((idx + 1).to_be_bytes()..) {
+            let (key, bytes) = delta?;
+            idx = u64::from_be_bytes(key.as_ref().try_into().map_err(|_| invalid("delta index"))?);
+            snapshot.merge(decode(&bytes)?);
+            found = true;
+        }
+        Ok(if found { Some((idx, snapshot)) } else { None })
+    }
+
+    /// Replaces the base with the merged chain and drops the deltas it covers.
+    pub fn consolidate(&self) -> sled::Result<()> {
+        let (idx, snapshot) = match self.read()? {
+            Some(chain) => chain,
+            None => return Ok(()),
+        };
+        self.db.insert(BASE_KEY, encode(&(idx, snapshot))?)?;
+        self.db.flush()?;
+        for key in self.deltas.range(..=idx.to_be_bytes()).keys() {
+            self.deltas.remove(key?)?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+fn encode<T: serde::Serialize>(value: &T) -> sled::Result<Vec<u8>> {
+    bincode::serialize(value).map_err(|e| invalid(&e.to_string()))
+}
+
+fn decode<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> sled::Result<T> {
+    bincode::deserialize(bytes).map_err(|e| invalid(&e.to_string()))
+}
+
+fn invalid(what: &str) -> sled::Error {
+    sled::Error::Io(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid snapshot {}", what)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::kv::{KVCommand, KVEntry, KeyValueIncrement};
+
+    fn open(dir: &tempfile::TempDir) -> SnapshotChain {
+        SnapshotChain::open(dir.path().to_string_lossy().into_owned()).unwrap()
+    }
+
+    #[test]
+    fn empty_chain_reads_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(open(&dir).read().unwrap().is_none());
+    }
+
+    #[test]
+    fn read_merges_the_deltas_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let chain = open(&dir);
+        chain.append(2, &KVSnapshot::create(&[KVEntry::put("a", 1), KVEntry::put("b", 2)])).unwrap();
+        chain.append(4, &KVSnapshot::create(&[KVEntry::put("a", 3), KVEntry::delete("b")])).unwrap();
+        let (idx, snapshot) = chain.read().unwrap().unwrap();
+        assert_eq!(idx, 4);
+        assert_eq!(snapshot.into_state(), HashMap::from([("a".to_string(), 3)]));
+    }
+
+    #[test]
+    fn consolidate_folds_the_deltas_into_the_base() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let chain = open(&dir);
+            chain.append(2, &KVSnapshot::create(&[KVEntry::put("a", 1), KVEntry::put("b", 2)])).unwrap();
+            chain.append(4, &KVSnapshot::create(&[KVEntry::delete("a")])).unwrap();
+            chain.consolidate().unwrap();
+            assert_eq!(chain.deltas(), 0);
+            chain.append(5, &KVSnapshot::create(&[KVEntry::put("c", 3)])).unwrap();
+        }
+        let chain = open(&dir);
+        assert_eq!(chain.deltas(), 1);
+        let (idx, snapshot) = chain.read().unwrap().unwrap();
+        assert_eq!(idx, 5);
+        assert_eq!(snapshot.into_state(), HashMap::from([("b".to_string(), 2), ("c".to_string(), 3)]));
+    }
+
+    #[test]
+    fn consolidation_interrupted_before_removing_the_deltas() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let chain = open(&dir);
+            chain.append(2, &KVSnapshot::create(&[KVEntry::put("a", 1)])).unwrap();
+            chain.append(4, &KVSnapshot::create(&[KVEntry {
+                proposal_id: 0,
+                client: None,
+                command: KVCommand::Increment(KeyValueIncrement { key: "a".to_string(), delta: 1 }),
+            }]))
+            .unwrap();
+            // the base is written, the deltas it covers are still there
+            let (idx, snapshot) = chain.read().unwrap().unwrap();
+            chain.db.insert(BASE_KEY, encode(&(idx, snapshot)).unwrap()).unwrap();
+            chain.db.flush().unwrap();
+        }
+        let chain = open(&dir);
+        assert_eq!(chain.deltas(), 2);
+        // the increment is not applied twice
+        let (idx, snapshot) = chain.read().unwrap().unwrap();
+        assert_eq!(idx, 4);
+        assert_eq!(snapshot.clone().into_state(), HashMap::from([("a".to_string(), 2)]));
+        chain.consolidate().unwrap();
+        assert_eq!(chain.deltas(), 0);
+        assert_eq!(chain.read().unwrap().unwrap().1.into_state(), snapshot.into_state());
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::iter;
+
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web::web::{Bytes, Json};
+use futures::stream;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::forward;
+use crate::kv::{KVOutcome, KVSnapshot};
+use crate::kv_controller::{bad_request, deadline, storage_error};
+use crate::storage::{export_snapshot, restore_snapshot};
+
+/// Keys or sessions serialized into one chunk of an exported snapshot.
+const CHUNK_ENTRIES: usize = 1000;
+
+/// A point-in-time copy of the cluster's state and the decided index it corresponds to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotResponse {
+    pub decided_idx: u64,
+    pub snapshot: KVSnapshot,
+}
+
+/// Exports the leader's state, which is linearizable like a default read, as a JSON
+/// `SnapshotResponse` streamed in chunks of `CHUNK_ENTRIES` keys.
+pub async fn get_snapshot(req: HttpRequest) -> HttpResponse {
+    let deadline = match deadline(&req) {
+        Ok(deadline) => deadline,
+        Err(error) => return bad_request(error),
+    };
+    if let Some(response) = forward::to_leader::<()>(&req, None, deadline).await {
+        return response;
+    }
+    let (decided_idx, snapshot) = match export_snapshot(deadline).await {
+        Ok(exported) => exported,
+        Err(error) => return storage_error(error),
+    };
+
+    let KVSnapshot { snapshotted, sessions, .. } = snapshot;
+    let body = iter::once(format!(r#"{{"decided_idx":{},"snapshot":{{"snapshotted":{{"#, decided_idx))
+        .chain(json_entries(snapshotted))
+        .chain(iter::once(String::from(r#"},"deleted":[],"sessions":{"#)))
+        .chain(json_entries(sessions))
+        .chain(iter::once(format!(r#"}},"pending":[],"len":{},"replaces":true}}}}"#, decided_idx)))
+        .map(|chunk| Ok::<_, actix_web::Error>(Bytes::from(chunk)));
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .status(StatusCode::OK)
+        .streaming(stream::iter(body))
+}
+
+/// The entries of a JSON object, serialized lazily in chunks of `CHUNK_ENTRIES`.
+fn json_entries<V: Serialize>(map: HashMap<String, V>) -> impl Iterator<Item = String> {
+    let mut entries = map.into_iter().peekable();
+    let mut first = true;
+    iter::from_fn(move || {
+        entries.peek()?;
+        let mut chunk = String::new();
+        for (key, value) in entries.by_ref().take(CHUNK_ENTRIES) {
+            if !first {
+                chunk.push(',');
+            }
+            first = false;
+            // strings, integers and sessions always serialize
+            chunk.push_str(&serde_json::to_string(&key).unwrap());
+            chunk.push(':');
+            chunk.push_str(&serde_json::to_string(&value).unwrap());
+        }
+        Some(chunk)
+    })
+}
+
+/// Loads an exported snapshot into a cluster that holds no keys yet, through a replicated
+/// restore so every replica ends up with the same state. Whether the cluster holds keys is
+/// decided when the restore is applied, a refused restore is answered with 409.
+pub async fn post_snapshot(req: HttpRequest, snapshot: Json<SnapshotResponse>) -> HttpResponse {
+    let deadline = match deadline(&req) {
+        Ok(deadline) => deadline,
+        Err(error) => return bad_request(error),
+    };
+    if let Some(response) = forward::to_leader(&req, Some(&*snapshot), deadline).await {
+        return response;
+    }
+    let SnapshotResponse { decided_idx: exported_idx, snapshot } = snapshot.into_inner();
+    let decided_idx = match restore_snapshot(snapshot, deadline).await {
+        Ok((_, KVOutcome::RestoreRefused { keys })) => {
+            info!(exported_idx, keys, "refused to restore snapshot");
+            return HttpResponse::Conflict()
+                .content_type("application/json")
+                .status(StatusCode::CONFLICT)
+                .json("A snapshot can only be restored into a cluster without keys");
+        }
+        Ok((decided_idx, _)) => decided_idx,
+        Err(error) => return storage_error(error),
+    };
+    info!(exported_idx, decided_idx, "restored snapshot");
+
+    HttpResponse::Created()
+        .content_type("application/json")
+        .status(StatusCode::CREATED)
+        .json(decided_idx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_entries_stream_one_object() {
+        let map: HashMap<String, u64> = (0..2500).map(|i| (format!("key{}", i), i)).collect();
+        let chunks: Vec<String> = json_entries(map.clone()).collect();
+        assert_eq!(chunks.len(), 3);
+        let parsed: HashMap<String, u64> = serde_json::from_str(&format!("{{{}}}", chunks.concat())).unwrap();
+        assert_eq!(parsed, map);
+        assert_eq!(json_entries(HashMap::<String, u64>::new()).count(), 0);
+    }
+}
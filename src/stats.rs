@@ -0,0 +1,133 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use omnipaxos_core::util::NodeId;
+use serde::{Deserialize, Serialize};
+
+/// Counters of a node, persisted in its storage directory so they survive restarts.
+pub struct NodeStats {
+    /// Client requests received by the node, gossiped to decide leader handover.
+    pub requests: AtomicU64,
+    pub creates: AtomicU64,
+    pub gets: AtomicU64,
+    pub cas: AtomicU64,
+    pub deletes: AtomicU64,
+    pub messages_sent: AtomicU64,
+    pub messages_received: AtomicU64,
+    pub send_errors: AtomicU64,
+    /// Times the leader compacted the log while this node led.
+    pub compactions: AtomicU64,
+    pub snapshot_deltas: AtomicU64,
+    pub consolidations: AtomicU64,
+    /// Bytes of decided entries applied since the last compaction, not persisted.
+    pub log_bytes: AtomicU64,
+    db: sled::Db,
+}
+
+impl NodeStats {
+    /// Opens the counters stored at `path`, starting from 0 if there are none.
+    pub fn open(path: String) -> sled::Result<Self> {
+        let db = sled::open(path)?;
+        let load = |name: &str| -> sled::Result<AtomicU64> {
+            let value = db
+                .get(name)?
+                .and_then(|bytes| bytes.as_ref().try_into().ok())
+                .map(u64::from_be_bytes)
+                .unwrap_or(0);
+            Ok(AtomicU64::new(value))
+        };
+        Ok(NodeStats {
+            requests: load("requests")?,
+            creates: load("creates")?,
+            gets: load("gets")?,
+            cas: load("cas")?,
+            deletes: load("deletes")?,
+            messages_sent: load("messages_sent")?,
+            messages_received: load("messages_received")?,
+            send_errors: load("send_errors")?,
+            compactions: load("compactions")?,
+            snapshot_deltas: load("snapshot_deltas")?,
+            consolidations: load("consolidations")?,
+            log_bytes: AtomicU64::new(0),
+            db,
+        })
+    }
+
+    fn counters(&self) -> [(&str, &AtomicU64); 11] {
+        [
+            ("requests", &self.requests),
+            ("creates", &self.creates),
+            ("gets", &self.gets),
+            ("cas", &self.cas),
+            ("deletes", &self.deletes),
+            ("messages_sent", &self.messages_sent),
+            ("messages_received", &self.messages_received),
+            ("send_errors", &self.send_errors),
+            ("compactions", &self.compactions),
+            ("snapshot_deltas", &self.snapshot_deltas),
+            ("consolidations", &self.consolidations),
+        ]
+    }
+
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn persist(&self) -> sled::Result<()> {
+        for (name, counter) in self.counters() {
+            self.db.insert(name, &counter.load(Ordering::Relaxed).to_be_bytes())?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeStatsResponse {
+    pub pid: NodeId,
+    pub requests: u64,
+    pub creates: u64,
+    pub gets: u64,
+    pub cas: u64,
+    pub deletes: u64,
+    pub decided_idx: u64,
+    pub applied_idx: u64,
+    /// The leader as seen by this node.
+    pub leader: Option<NodeId>,
+    /// Entries still in the log, the decided ones that were not compacted.
+    pub log_len: u64,
+    pub compacted_idx: u64,
+    pub compactions: u64,
+    pub snapshot_deltas: u64,
+    pub consolidations: u64,
+    /// Bytes of the entries decided since the last compaction, compared to `max_bytes`.
+    pub log_bytes: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub send_errors: u64,
+}
+
+impl NodeStatsResponse {
+    pub fn new(pid: NodeId, stats: &NodeStats, decided_idx: u64, applied_idx: u64, leader: Option<NodeId>, compacted_idx: u64) -> Self {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        NodeStatsResponse {
+            pid,
+            requests: load(&stats.requests),
+            creates: load(&stats.creates),
+            gets: load(&stats.gets),
+            cas: load(&stats.cas),
+            deletes: load(&stats.deletes),
+            decided_idx,
+            applied_idx,
+            leader,
+            log_len: decided_idx.saturating_sub(compacted_idx),
+            compacted_idx,
+            compactions: load(&stats.compactions),
+            snapshot_deltas: load(&stats.snapshot_deltas),
+            consolidations: load(&stats.consolidations),
+            log_bytes: load(&stats.log_bytes),
+            messages_sent: load(&stats.messages_sent),
+            messages_received: load(&stats.messages_received),
+            send_errors: load(&stats.send_errors),
+        }
+    }
+}
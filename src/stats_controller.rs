@@ -0,0 +1,91 @@
+use actix_web::get;
+use actix_web::HttpResponse;
+use actix_web::web::Path;
+use http::StatusCode;
+use omnipaxos_core::util::NodeId;
+use prometheus::{Encoder, TextEncoder, TEXT_FORMAT};
+
+use crate::{CLUSTER, OP_SERVER_HANDLERS};
+use crate::forward::CLIENT;
+use crate::metrics;
+use crate::stats::NodeStatsResponse;
+use crate::util::STATS_FETCH_TIMEOUT;
+
+#[get("/nodes/{pid}/stats")]
+pub async fn node_stats(pid: Path<NodeId>) -> HttpResponse {
+    match stats_of(pid.into_inner()).await {
+        Some(response) => HttpResponse::Ok()
+            .content_type("application/json")
+            .status(StatusCode::OK)
+            .json(response),
+        None => HttpResponse::NotFound()
+            .content_type("application/json")
+            .status(StatusCode::NOT_FOUND)
+            .finish(),
+    }
+}
+
+/// The statistics of every node in the cluster that could be reached.
+#[get("/cluster/stats")]
+pub async fn cluster_stats() -> HttpResponse {
+    let pids: Vec<NodeId> = CLUSTER.read().unwrap().nodes.iter().map(|node| node.pid).collect();
+    let mut response = Vec::new();
+    for pid in pids {
+        if let Some(stats) = stats_of(pid).await {
+            response.push(stats);
+        }
+    }
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .status(StatusCode::OK)
+        .json(response)
+}
+
+/// Every node's metrics in the Prometheus text format.
+#[get("/metrics")]
+pub async fn prometheus_metrics() -> HttpResponse {
+    let mut buffer = Vec::new();
+    if let Err(err) = TextEncoder::new().encode(&metrics::REGISTRY.gather(), &mut buffer) {
+        return HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .json(err.to_string());
+    }
+    HttpResponse::Ok()
+        .content_type(TEXT_FORMAT)
+        .status(StatusCode::OK)
+        .body(buffer)
+}
+
+/// Reads the statistics of a node in this process, or asks the node's own REST API.
+async fn stats_of(pid: NodeId) -> Option<NodeStatsResponse> {
+    if let Some(response) = local_stats(pid) {
+        return Some(response);
+    }
+    let node = CLUSTER.read().unwrap().node(pid).cloned()?;
+    let response = CLIENT
+        .get(format!("http://{}/nodes/{}/stats", node.http_address, pid))
+        .timeout(STATS_FETCH_TIMEOUT)
+        .send()
+        .await
+        .ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    response.json().await.ok()
+}
+
+fn local_stats(pid: NodeId) -> Option<NodeStatsResponse> {
+    let handler = OP_SERVER_HANDLERS.lock().unwrap();
+    let node = handler.get(&pid)?;
+    let applied_idx = *node.applied_idx.borrow();
+    let omni_paxos = node.omni_paxos.lock().unwrap();
+    Some(NodeStatsResponse::new(
+        pid,
+        &node.stats,
+        node.base_idx + omni_paxos.get_decided_idx(),
+        applied_idx,
+        omni_paxos.get_current_leader(),
+        node.base_idx + omni_paxos.get_compacted_idx(),
+    ))
+}
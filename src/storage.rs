@@ -1,29 +1,115 @@
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-use omnipaxos_core::util::LogEntry;
+use lazy_static::lazy_static;
+use omnipaxos_core::util::NodeId;
 use rand::Rng;
+use tokio::sync::{oneshot, watch};
+use tokio::time::timeout_at;
+use tracing::{debug, instrument, warn, Span};
 
-use crate::{KeyValue, SERVERS, WAIT_DECIDED_TIMEOUT};
-use crate::kv::KeyValueCas;
+use crate::KeyValue;
+use crate::kv::{ClientRequest, KVCommand, KVEntry, KVOutcome, KVSnapshot, KeyValueCas};
 use crate::kv_controller::KeyValueResponse;
-use crate::nodes::KVStore;
-use crate::nodes::STORAGE_REPLICAS;
-use crate::OP_SERVER_HANDLERS;
+use crate::nodes::{KVStore, NodeHandler};
+use crate::metrics;
+use crate::stats::NodeStats;
+use crate::{CLUSTER, DEPLOYMENT, OP_SERVER_HANDLERS};
+use crate::util::{LEADER_LEASE_DURATION, MAX_ENTRY_SIZE};
 
-const PEERS: u64 = SERVERS.len() as u64;
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadConsistency {
+    /// Confirms leadership with a quorum by deciding a read barrier before serving.
+    Linearizable,
+    /// Serves from the leader without a quorum round while its lease holds, linearizable
+    /// otherwise. Never holds a lease while handover can move leadership.
+    Lease,
+    /// Serves the current leader's applied state without confirming its leadership.
+    Leader,
+    /// Serves from any replica, possibly missing recent writes.
+    Stale,
+    /// Serves from any replica whose applied index is at most this far behind the leader.
+    Bounded(u64),
+}
 
-pub async fn get_kv(key: String) -> KeyValueResponse {
-    let mut is_failed = false;
-    if cfg!(feature="test") && rand::thread_rng().gen_range(1..10) > 7 {
-        is_failed = true;
-        println!("A storage was disconnected")
+impl ReadConsistency {
+    fn name(&self) -> &'static str {
+        match self {
+            ReadConsistency::Linearizable => "linearizable",
+            ReadConsistency::Lease => "lease",
+            ReadConsistency::Leader => "leader",
+            ReadConsistency::Stale => "stale",
+            ReadConsistency::Bounded(_) => "bounded",
+        }
     }
-    let replica_id = rand::thread_rng().gen_range(0..STORAGE_REPLICAS.len());
-    sync_decided_kv(replica_id, is_failed).await;
+}
+
+lazy_static! {
+    // the leader and the time it last appended an entry that was decided afterwards
+    static ref LEADER_LEASE: Mutex<Option<(NodeId, Instant)>> = Mutex::new(None);
+}
 
-    let kv_store = KVStore::get_storage(replica_id, is_failed);
+#[derive(Clone, Debug, PartialEq)]
+pub enum StorageError {
+    /// The request did not complete before its deadline.
+    Timeout,
+    /// No leader is known, or it could not take the proposal.
+    Unavailable,
+    /// The client already had a later sequence number applied, so this request is neither
+    /// applied nor answered with its original outcome.
+    Superseded,
+    /// The entry is larger than `MAX_ENTRY_SIZE`, the nodes could not replicate it.
+    TooLarge,
+}
+
+/// A replica's id, its store and the index its apply loop has reached.
+type Replica = (NodeId, Arc<Mutex<KVStore>>, watch::Receiver<u64>);
 
-    println!("Get value by replica {:?}", replica_id);
+/// Reads `key` with the given consistency. If `min_decided_idx` is set the read is served
+/// only once the chosen replica has applied at least that index.
+#[instrument(level = "debug", skip(deadline))]
+pub async fn get_kv(
+    key: String,
+    consistency: ReadConsistency,
+    min_decided_idx: Option<u64>,
+    deadline: Instant,
+) -> Result<KeyValueResponse, StorageError> {
+    let started_at = Instant::now();
+    let (replica_id, kv_store, mut applied_idx) = match consistency {
+        ReadConsistency::Linearizable => read_index(deadline).await?,
+        ReadConsistency::Lease => match lease_read_index(deadline).await? {
+            Some(read) => read,
+            None => read_index(deadline).await?,
+        },
+        ReadConsistency::Leader => {
+            let handler = OP_SERVER_HANDLERS.lock().unwrap();
+            let leader_id = current_leader(&handler).ok_or(StorageError::Unavailable)?;
+            replica(&handler, leader_id)?
+        }
+        ReadConsistency::Stale => {
+            let handler = OP_SERVER_HANDLERS.lock().unwrap();
+            let pids: Vec<&u64> = handler.keys().collect();
+            // a process whose node is not part of the configuration runs no replica
+            if pids.is_empty() {
+                return Err(StorageError::Unavailable);
+            }
+            let replica_id = *pids[rand::thread_rng().gen_range(0..pids.len())];
+            replica(&handler, replica_id)?
+        }
+        ReadConsistency::Bounded(max_lag) => bounded_replica(max_lag, deadline).await?,
+    };
+
+    if let Some(min_idx) = min_decided_idx {
+        wait_applied(&mut applied_idx, min_idx, deadline).await?;
+    }
+    count_served(replica_id, |stats| &stats.gets);
+    metrics::READ_LATENCY
+        .with_label_values(&[&replica_id.to_string(), consistency.name()])
+        .observe(started_at.elapsed().as_secs_f64());
+
+    debug!(replica_id, ?consistency, "reading");
     let storage = kv_store.lock().unwrap();
 
     let value = storage.key_value.get(key.as_str());
@@ -44,134 +130,339 @@ pub async fn get_kv(key: String) -> KeyValueResponse {
             };
         }
     }
-    response.clone()
+    Ok(response)
 }
 
-pub async fn cas_kv(kv: KeyValueCas) -> u64 {
-    let read_response = get_kv(kv.key.clone()).await;
-    if read_response.value == kv.old_value {
-        let new_kv = KeyValue {
-            key: kv.key,
-            value: kv.new_value,
-        };
-        return create_kv(new_kv).await;
-    } else {
-        return 0;
+/// Returns the leader's full state once it has applied a read barrier, together with the
+/// decided index the state reflects.
+#[instrument(level = "debug", skip(deadline))]
+pub async fn export_snapshot(deadline: Instant) -> Result<(u64, KVSnapshot), StorageError> {
+    let (leader_id, kv_store, _) = read_index(deadline).await?;
+    let storage = kv_store.lock().unwrap();
+    debug!(leader_id, decided_idx = storage.decided_idx, keys = storage.key_value.len(), "exporting");
+    Ok((storage.decided_idx, KVSnapshot::of(&storage.key_value, &storage.sessions, storage.decided_idx)))
+}
+
+/// Appends a restore that loads the keys of `snapshot` into every replica and returns its
+/// decided index together with the outcome, which refuses it if the store held any key when
+/// it was applied. A snapshot whose entry exceeds `MAX_ENTRY_SIZE` is refused up front.
+#[instrument(level = "debug", skip_all)]
+pub async fn restore_snapshot(snapshot: KVSnapshot, deadline: Instant) -> Result<(u64, KVOutcome), StorageError> {
+    let entry = KVEntry { proposal_id: 0, client: None, command: KVCommand::Restore(snapshot.into_state()) };
+    // the bincode encoding sent between nodes, which can be larger than the JSON it came from
+    let size = bincode::serialized_size(&entry).unwrap_or(u64::MAX);
+    if size > MAX_ENTRY_SIZE {
+        warn!(size, max = MAX_ENTRY_SIZE, "snapshot too large to restore");
+        return Err(StorageError::TooLarge);
     }
+    propose(entry.command, None, deadline).await
 }
 
-pub async fn create_kv(kv: KeyValue) -> u64 {
-    // sync_decided_kv().await;
+/// Decides a read barrier and returns the leader, which has applied it by then.
+async fn read_index(deadline: Instant) -> Result<Replica, StorageError> {
+    let (leader_id, _, _) = append_command(KVCommand::ReadBarrier, None, deadline).await?;
+    let handler = OP_SERVER_HANDLERS.lock().unwrap();
+    replica(&handler, leader_id)
+}
 
-    let mut is_failed = false;
-    if cfg!(feature="test") && rand::thread_rng().gen_range(1..10) > 7 {
-        is_failed = true;
-        println!("The last storage was disconnected")
+/// Returns the leader caught up to its decided index if the leader still holds its lease.
+/// There is no lease while handover can move leadership, since raising a node's priority lets
+/// it take over at the end of any heartbeat round, well within a lease.
+async fn lease_read_index(deadline: Instant) -> Result<Option<Replica>, StorageError> {
+    // with every node in this process handover never moves leadership, see `nodes::count_request`
+    let single_node = DEPLOYMENT.read().unwrap().node_id.is_some();
+    if single_node && CLUSTER.read().unwrap().handover.enabled {
+        return Ok(None);
     }
-    let replica_id = rand::thread_rng().gen_range(0..STORAGE_REPLICAS.len());
+    let (leader_id, granted_at) = match *LEADER_LEASE.lock().unwrap() {
+        Some(lease) => lease,
+        None => return Ok(None),
+    };
+    if granted_at.elapsed() >= LEADER_LEASE_DURATION {
+        return Ok(None);
+    }
+    let (leader, read_idx) = {
+        let handler = OP_SERVER_HANDLERS.lock().unwrap();
+        let read_idx = match handler.get(&leader_id) {
+            Some(node) => {
+                let omni_paxos = node.omni_paxos.lock().unwrap();
+                if omni_paxos.get_current_leader() != Some(leader_id) {
+                    return Ok(None);
+                }
+                node.base_idx + omni_paxos.get_decided_idx()
+            }
+            None => return Ok(None),
+        };
+        (replica(&handler, leader_id)?, read_idx)
+    };
+    let (_, _, mut applied_idx) = leader.clone();
+    wait_applied(&mut applied_idx, read_idx, deadline).await?;
+    Ok(Some(leader))
+}
 
-    let kv_store = KVStore::get_storage(replica_id, is_failed);
-    let storage = kv_store.lock().unwrap();
-    storage.key_value.get(&kv.key);
+/// Picks a random replica that has applied at least the leader's decided index minus `max_lag`,
+/// waiting for the leader itself if none has.
+async fn bounded_replica(max_lag: u64, deadline: Instant) -> Result<Replica, StorageError> {
+    let (leader, candidates, min_idx) = {
+        let handler = OP_SERVER_HANDLERS.lock().unwrap();
+        let leader_id = current_leader(&handler).ok_or(StorageError::Unavailable)?;
+        let leader = handler.get(&leader_id).ok_or(StorageError::Unavailable)?;
+        let min_idx = (leader.base_idx + leader.omni_paxos.lock().unwrap().get_decided_idx()).saturating_sub(max_lag);
+        let candidates: Vec<Replica> = handler
+            .iter()
+            .filter(|(_, node)| *node.applied_idx.borrow() >= min_idx)
+            .map(|(pid, node)| (*pid, Arc::clone(&node.kv_store), node.applied_idx.clone()))
+            .collect();
+        (replica(&handler, leader_id)?, candidates, min_idx)
+    };
+    if candidates.is_empty() {
+        let (_, _, mut applied_idx) = leader.clone();
+        wait_applied(&mut applied_idx, min_idx, deadline).await?;
+        return Ok(leader);
+    }
+    Ok(candidates[rand::thread_rng().gen_range(0..candidates.len())].clone())
+}
 
-    let handler = OP_SERVER_HANDLERS.lock().unwrap();
-    let server_id = rand::thread_rng().gen_range(1..PEERS);
-    let (server, _, _) = handler.get(&server_id).unwrap();
+fn replica(handler: &HashMap<NodeId, NodeHandler>, pid: NodeId) -> Result<Replica, StorageError> {
+    let node = handler.get(&pid).ok_or(StorageError::Unavailable)?;
+    Ok((pid, Arc::clone(&node.kv_store), node.applied_idx.clone()))
+}
 
-    let leader_id = server
+/// Asks a random node running in this process for the leader.
+pub(crate) fn current_leader(handler: &HashMap<NodeId, NodeHandler>) -> Option<NodeId> {
+    let pids: Vec<&u64> = handler.keys().collect();
+    if pids.is_empty() {
+        return None;
+    }
+    let server_id = *pids[rand::thread_rng().gen_range(0..pids.len())];
+    handler
+        .get(&server_id)?
+        .omni_paxos
         .lock()
         .unwrap()
         .get_current_leader()
-        .expect("Failed to get leader");
-    let (leader, _, _) = handler.get(&leader_id).unwrap();
+}
 
-    let before_idx = leader
-        .lock()
-        .unwrap()
-        .get_decided_idx();
-    println!("Before index {}", before_idx);
+fn renew_lease(leader_id: NodeId, appended_at: Instant) {
+    let mut lease = LEADER_LEASE.lock().unwrap();
+    match *lease {
+        Some((pid, granted_at)) if pid == leader_id && granted_at >= appended_at => {}
+        _ => *lease = Some((leader_id, appended_at)),
+    }
+}
 
-    leader
-        .lock()
-        .unwrap()
-        .append(kv.clone())
-        .expect("append failed");
-
-    loop {
-        std::thread::sleep(WAIT_DECIDED_TIMEOUT);
-        let committed_ents = server
-            .lock()
-            .unwrap()
-            .read_decided_suffix(before_idx)
-            .expect("Failed to read expected entries");
-        for (i, ent) in committed_ents.iter().enumerate() {
-            match ent {
-                LogEntry::Decided(kv_decided) => {
-                    if kv.key == kv_decided.key {
-                        let new_idx = before_idx + (i as u64) + 1;
-                        println!("Adding value: {:?}, decided idx {} via server {} and replica {}",
-                                 kv, new_idx, leader_id, replica_id);
-                        return new_idx;
-                    }
-                }
-                _ => {} // ignore not committed entries
+/// Appends the CAS to the log and returns its decided index together with the
+/// outcome it had when it was applied in log order.
+#[instrument(level = "debug", skip(deadline))]
+pub async fn cas_kv(
+    kv: KeyValueCas,
+    client: Option<ClientRequest>,
+    deadline: Instant,
+) -> Result<(u64, KVOutcome), StorageError> {
+    propose(KVCommand::Cas(kv), client, deadline).await
+}
+
+/// Appends a delete for `key`, the outcome tells whether the key existed when it was applied.
+#[instrument(level = "debug", skip(deadline))]
+pub async fn delete_kv(
+    key: String,
+    client: Option<ClientRequest>,
+    deadline: Instant,
+) -> Result<(u64, KVOutcome), StorageError> {
+    propose(KVCommand::Delete(key), client, deadline).await
+}
+
+#[instrument(level = "debug", skip(deadline))]
+pub async fn create_kv(
+    kv: KeyValue,
+    client: Option<ClientRequest>,
+    deadline: Instant,
+) -> Result<u64, StorageError> {
+    let (decided_idx, _) = propose(KVCommand::Put(kv), client, deadline).await?;
+    Ok(decided_idx)
+}
+
+/// Appends `command` and returns its decided index and outcome. A retried client request
+/// returns the index and outcome of the original instead of being applied again.
+async fn propose(
+    command: KVCommand,
+    client: Option<ClientRequest>,
+    deadline: Instant,
+) -> Result<(u64, KVOutcome), StorageError> {
+    if let Some(applied) = client.as_ref().map(applied_request).transpose()?.flatten() {
+        return Ok(applied);
+    }
+    let counter: Option<fn(&NodeStats) -> &AtomicU64> = match &command {
+        KVCommand::Put(_) => Some(|stats| &stats.creates),
+        KVCommand::Cas(_) => Some(|stats| &stats.cas),
+        KVCommand::Delete(_) => Some(|stats| &stats.deletes),
+        _ => None,
+    };
+    let is_cas = matches!(command, KVCommand::Cas(_));
+    let (leader_id, decided_idx, outcome) = append_command(command, client, deadline).await?;
+    if let Some(counter) = counter {
+        count_served(leader_id, counter);
+    }
+    let (decided_idx, outcome) = match outcome {
+        KVOutcome::Duplicate { decided_idx, outcome } => (decided_idx, *outcome),
+        KVOutcome::Superseded { .. } => return Err(StorageError::Superseded),
+        outcome => (decided_idx, outcome),
+    };
+    if is_cas {
+        let result = if let KVOutcome::CasFailed { .. } = outcome { "failure" } else { "success" };
+        metrics::CAS_TOTAL.with_label_values(&[&leader_id.to_string(), result]).inc();
+    }
+    Ok((decided_idx, outcome))
+}
+
+/// Counts a request served by node `pid` in the given counter of its statistics.
+fn count_served(pid: NodeId, counter: fn(&NodeStats) -> &AtomicU64) {
+    if let Some(node) = OP_SERVER_HANDLERS.lock().unwrap().get(&pid) {
+        NodeStats::incr(counter(&node.stats));
+    }
+}
+
+/// Looks up `client` in the deduplication tables of the replicas. Fails if any replica has
+/// applied a later sequence number of the client.
+fn applied_request(client: &ClientRequest) -> Result<Option<(u64, KVOutcome)>, StorageError> {
+    let handler = OP_SERVER_HANDLERS.lock().unwrap();
+    for node in handler.values() {
+        let storage = node.kv_store.lock().unwrap();
+        match storage.sessions.get(&client.client_id) {
+            Some(session) if session.seq > client.seq => return Err(StorageError::Superseded),
+            Some(session) if session.seq == client.seq => {
+                return Ok(Some((session.decided_idx, session.outcome.clone())))
             }
+            _ => {}
         }
     }
+    Ok(None)
 }
 
-async fn sync_decided_kv(replica_id: usize, is_failed: bool) {
-    let kv_store = KVStore::get_storage(replica_id, is_failed);
-    let mut storage = kv_store.lock().unwrap();
+/// Appends `command` via the current leader and waits until the leader has applied it.
+/// Returns the leader's id, the decided index and the outcome of the entry.
+#[instrument(level = "debug", skip_all, fields(proposal_id))]
+async fn append_command(
+    command: KVCommand,
+    client: Option<ClientRequest>,
+    deadline: Instant,
+) -> Result<(NodeId, u64, KVOutcome), StorageError> {
+    let entry = KVEntry {
+        proposal_id: rand::random(),
+        client,
+        command,
+    };
+    Span::current().record("proposal_id", entry.proposal_id);
+    let (sender, receiver) = oneshot::channel();
 
-    let handler = OP_SERVER_HANDLERS.lock().unwrap();
-    let mut server_id = rand::thread_rng().gen_range(1..PEERS);
-    // println!("Chosen server {}", server_id);
-    let (server, _, _) = handler.get(&server_id).unwrap();
+    // no lock is held while waiting for the decision
+    let (leader_id, appended_at, proposals) = {
+        let handler = OP_SERVER_HANDLERS.lock().unwrap();
+        let leader_id = current_leader(&handler).ok_or(StorageError::Unavailable)?;
+        let leader = handler.get(&leader_id).ok_or(StorageError::Unavailable)?;
+        leader.proposals.lock().unwrap().insert(entry.proposal_id, sender);
 
-    let mut last_idx = server
-        .lock()
-        .unwrap()
-        .get_decided_idx();
-    for n in 1..(PEERS / 2 + 1) as u64 {
-        let (server, _, _) = handler.get(&n).unwrap();
-        let tmp_idx = server
-            .lock()
-            .unwrap()
-            .get_decided_idx();
-        if tmp_idx > last_idx {
-            last_idx = tmp_idx;
-            server_id = n;
+        let appended_at = Instant::now();
+        let appended = leader.omni_paxos.lock().unwrap().append(entry.clone());
+        if let Err(err) = appended {
+            warn!(leader_id, error = ?err, "append to the leader failed");
+            leader.proposals.lock().unwrap().remove(&entry.proposal_id);
+            return Err(StorageError::Unavailable);
+        }
+        (leader_id, appended_at, Arc::clone(&leader.proposals))
+    };
+
+    match timeout_at(deadline.into(), receiver).await {
+        Ok(Ok((decided_idx, outcome))) => {
+            debug!(decided_idx, leader_id, "entry decided and applied");
+            renew_lease(leader_id, appended_at);
+            if entry.command != KVCommand::ReadBarrier {
+                metrics::WRITE_LATENCY
+                    .with_label_values(&[&leader_id.to_string()])
+                    .observe(appended_at.elapsed().as_secs_f64());
+            }
+            Ok((leader_id, decided_idx, outcome))
+        }
+        Ok(Err(_)) => Err(StorageError::Unavailable),
+        Err(_) => {
+            // the entry may still be decided later, a retry with the same client request is safe
+            proposals.lock().unwrap().remove(&entry.proposal_id);
+            Err(StorageError::Timeout)
         }
     }
-    println!("Last index {}", last_idx);
-    println!("Local index {}", storage.decided_idx);
-
-    if last_idx > storage.decided_idx {
-        let committed_ents = server
-            .lock()
-            .unwrap()
-            .read_decided_suffix(storage.decided_idx as u64)
-            .expect("Failed to read expected entries");
-
-        for (_, ent) in committed_ents.iter().enumerate() {
-            match ent {
-                LogEntry::Decided(kv_decided) => {
-                    storage.decided_idx += 1;
-                    storage.key_value.insert(kv_decided.key.clone(), kv_decided.value);
-                    println!("Adding value: {:?}, decided idx {} via server {}",
-                             kv_decided.value, storage.decided_idx, server_id);
-                }
-                LogEntry::Snapshotted(kv_snapshotted) => {
-                    for (k, v) in &kv_snapshotted.snapshot.snapshotted {
-                        storage.decided_idx += 1;
-                        storage.key_value.insert(k.clone(), *v);
-                        println!("Adding value: {:?}, decided inx {} via server {}",
-                                 v, storage.decided_idx, server_id);
-                    }
-                }
-                _ => {} // ignore not committed entries
+}
+
+/// Waits until the replica's apply loop has reached `decided_idx`.
+async fn wait_applied(
+    applied_idx: &mut watch::Receiver<u64>,
+    decided_idx: u64,
+    deadline: Instant,
+) -> Result<(), StorageError> {
+    let applied = async {
+        while *applied_idx.borrow() < decided_idx {
+            if applied_idx.changed().await.is_err() {
+                return Err(StorageError::Unavailable);
             }
         }
+        Ok(())
+    };
+    timeout_at(deadline.into(), applied)
+        .await
+        .unwrap_or(Err(StorageError::Timeout))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use omnipaxos_core::storage::{Snapshot, StopSign};
+    use omnipaxos_core::util::LogEntry;
+
+    use super::*;
+    use crate::membership::ConfigurationState;
+
+    #[tokio::test]
+    async fn restore_refuses_snapshots_larger_than_an_entry() {
+        let key = "k".repeat(MAX_ENTRY_SIZE as usize);
+        let snapshot = KVSnapshot::create(&[KVEntry::put(&key, 1)]);
+        let restored = restore_snapshot(snapshot, Instant::now() + Duration::from_secs(1)).await;
+        assert_eq!(restored.unwrap_err(), StorageError::TooLarge);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn tokens_from_before_a_reconfiguration_are_accepted() {
+        let mut kv_store = KVStore::new();
+        let stop_sign = StopSign { config_id: 2, nodes: vec![1, 2, 3], metadata: None };
+        let applied = kv_store
+            .apply_entries(&[
+                LogEntry::Decided(KVEntry::put("a", 1).with_client("c", 1)),
+                LogEntry::Decided(KVEntry::put("b", 2).with_client("c", 2)),
+                LogEntry::StopSign(stop_sign),
+            ])
+            .unwrap();
+        let token = applied[1].1;
+        let state = ConfigurationState {
+            configuration_id: 2,
+            nodes: Vec::new(),
+            snapshot: KVSnapshot::of(&kv_store.key_value, &kv_store.sessions, kv_store.decided_idx),
+        };
+        let mut next = state.kv_store();
+        assert_eq!((next.decided_idx, next.log_idx()), (3, 0));
+        // a node of the new configuration starts its apply loop at the stop-sign's index
+        let (_, mut applied_idx) = watch::channel(next.decided_idx);
+        wait_applied(&mut applied_idx, token, Instant::now() + Duration::from_millis(10)).await.unwrap();
+
+        // the new log continues the numbering and retries answer with their original index
+        let entries = [KVEntry::put("b", 2).with_client("c", 2), KVEntry::put("a", 3).with_client("c", 3)];
+        let applied = next.apply_entries(&entries.clone().map(LogEntry::Decided)).unwrap();
+        assert!(matches!(applied[0].2, KVOutcome::Duplicate { decided_idx, .. } if decided_idx == token));
+        assert_eq!(applied[1].1, 5);
+
+        // a snapshot of the new log builds on the state the configuration started from
+        let mut restarted = state.kv_store();
+        restarted.install_log(2, KVSnapshot::create(&entries));
+        assert_eq!(restarted.decided_idx, 5);
+        assert_eq!(restarted.key_value, next.key_value);
+        assert_eq!(restarted.sessions["c"].decided_idx, 5);
+    }
+}
@@ -3,6 +3,19 @@ use std::time::Duration;
 pub const BUFFER_SIZE: usize = 10000;
 pub const ELECTION_TIMEOUT: Duration = Duration::from_millis(100);
 pub const OUTGOING_MESSAGE_PERIOD: Duration = Duration::from_millis(100);
+pub const APPLY_PERIOD: Duration = Duration::from_millis(10);
+pub const GOSSIP_PERIOD: Duration = Duration::from_millis(500);
+pub const COMPACTION_PERIOD: Duration = Duration::from_secs(1);
+pub const STATS_PERSIST_PERIOD: Duration = Duration::from_secs(1);
+pub const STATS_FETCH_TIMEOUT: Duration = Duration::from_secs(1);
 
-pub const WAIT_LEADER_TIMEOUT: Duration = Duration::from_millis(500);
-pub const WAIT_DECIDED_TIMEOUT: Duration = Duration::from_millis(250);
\ No newline at end of file
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// Shorter than ELECTION_TIMEOUT, so with equal priorities a follower still heard the leader in its
+// last heartbeat round while the lease holds. Priority changes break this, so handover disables it.
+pub const LEADER_LEASE_DURATION: Duration = Duration::from_millis(80);
+pub const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(50);
+pub const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(2);
+pub const MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024;
+// An entry travels between nodes inside a message, possibly batched with other entries, so it
+// has to stay well below a frame.
+pub const MAX_ENTRY_SIZE: u64 = MAX_FRAME_SIZE as u64 / 2;
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use actix_web::http::StatusCode;
 use futures::{FutureExt, stream, StreamExt};
 use restest::{assert_body_matches, Context, path, Request};
@@ -82,6 +84,60 @@ async fn test_multiple_create() {
     }).await;
 }
 
+#[tokio::test]
+async fn test_create_retry() {
+    let request = || {
+        Request::post(path!["key-value"])
+            .with_header("ContentType", "application/json")
+            .with_header("X-Client-Id", "test_create_retry")
+            .with_header("X-Client-Seq", "1")
+            .with_body(KeyValue {
+                key: String::from("d"),
+                value: 4,
+            })
+    };
+
+    let first: KeyValueResponse = CONTEXT
+        .run(request())
+        .await
+        .expect_status(StatusCode::CREATED)
+        .await;
+
+    let retry: KeyValueResponse = CONTEXT
+        .run(request())
+        .await
+        .expect_status(StatusCode::CREATED)
+        .await;
+
+    assert_eq!(first, retry);
+}
+
+#[tokio::test]
+async fn test_create_superseded_retry() {
+    let request = |seq: &str| {
+        Request::post(path!["key-value"])
+            .with_header("ContentType", "application/json")
+            .with_header("X-Client-Id", "test_create_superseded_retry")
+            .with_header("X-Client-Seq", seq)
+            .with_body(KeyValue {
+                key: String::from("e"),
+                value: 5,
+            })
+    };
+
+    CONTEXT
+        .run(request("2"))
+        .await
+        .expect_status::<KeyValueResponse>(StatusCode::CREATED)
+        .await;
+
+    CONTEXT
+        .run(request("1"))
+        .await
+        .expect_status::<String>(StatusCode::CONFLICT)
+        .await;
+}
+
 #[tokio::test]
 async fn test_get() {
     let request = Request::post(path!["key-value"])
@@ -118,6 +174,79 @@ async fn test_get() {
     }
 }
 
+#[tokio::test]
+async fn test_get_bounded() {
+    let request = Request::post(path!["key-value"])
+        .with_header("ContentType", "application/json")
+        .with_body(KeyValue {
+            key: String::from("b"),
+            value: 7,
+        });
+
+    CONTEXT
+        .run(request)
+        .await
+        .expect_status::<KeyValueResponse>(StatusCode::CREATED)
+        .await;
+
+    let request = Request::get(path!["key-value/b?consistency=bounded&max_lag=0"])
+        .with_header("ContentType", "application/json")
+        .with_body("");
+
+    let body = CONTEXT
+        .run(request)
+        .await
+        .expect_status(StatusCode::OK)
+        .await;
+
+    assert_body_matches! {
+        body,
+        KeyValueResponse { key: "b", value: 7,..}
+    }
+
+    let request = Request::get(path!["key-value/b?consistency=bounded"])
+        .with_header("ContentType", "application/json")
+        .with_body("");
+
+    CONTEXT
+        .run(request)
+        .await
+        .expect_status::<String>(StatusCode::BAD_REQUEST)
+        .await;
+}
+
+#[tokio::test]
+async fn test_get_min_decided_idx() {
+    let request = Request::post(path!["key-value"])
+        .with_header("ContentType", "application/json")
+        .with_body(KeyValue {
+            key: String::from("c"),
+            value: 3,
+        });
+
+    let created: KeyValueResponse = CONTEXT
+        .run(request)
+        .await
+        .expect_status(StatusCode::CREATED)
+        .await;
+
+    let request = Request::get(path!["key-value/c?consistency=stale"])
+        .with_header("ContentType", "application/json")
+        .with_header("X-Min-Decided-Idx", created.decided_idx.to_string())
+        .with_body("");
+
+    let body = CONTEXT
+        .run(request)
+        .await
+        .expect_status(StatusCode::OK)
+        .await;
+
+    assert_body_matches! {
+        body,
+        KeyValueResponse { key: "c", value: 3,..}
+    }
+}
+
 #[tokio::test]
 async fn test_cas() {
     let create_request = Request::post(path!["key-value"])
@@ -163,7 +292,7 @@ async fn test_unsuccessful_cas() {
     let create_request = Request::post(path!["key-value"])
         .with_header("ContentType", "application/json")
         .with_body(KeyValue {
-            key: String::from("a"),
+            key: String::from("cas_failed"),
             value: 1,
         });
 
@@ -175,13 +304,13 @@ async fn test_unsuccessful_cas() {
 
     assert_body_matches! {
         body,
-        KeyValueResponse { key: "a", value: 1,..}
+        KeyValueResponse { key: "cas_failed", value: 1,..}
     }
 
     let cas_request = Request::post(path!["key-value/cas"])
         .with_header("ContentType", "application/json")
         .with_body(KeyValueCas {
-            key: String::from("a"),
+            key: String::from("cas_failed"),
             old_value: 10,
             new_value: 2,
         });
@@ -189,16 +318,164 @@ async fn test_unsuccessful_cas() {
     let body = CONTEXT
         .run(cas_request)
         .await
-        .expect_status::<String>(StatusCode::BAD_REQUEST)
+        .expect_status(StatusCode::BAD_REQUEST)
         .await;
 
     assert_body_matches! {
         body,
-        "Could not CAS KV since the old value was different"
+        CasFailedResponse {
+            key: "cas_failed",
+            current_value: Some(1),
+            error: "Could not CAS KV since the old value was different",
+            ..
+        }
     }
 }
 
 
+#[tokio::test]
+async fn test_delete() {
+    let request = Request::post(path!["key-value"])
+        .with_header("ContentType", "application/json")
+        .with_body(KeyValue {
+            key: String::from("deleted"),
+            value: 7,
+        });
+
+    CONTEXT
+        .run(request)
+        .await
+        .expect_status::<KeyValueResponse>(StatusCode::CREATED)
+        .await;
+
+    let request = || {
+        Request::delete(path!["key-value/deleted"])
+            .with_header("ContentType", "application/json")
+            .with_body("")
+    };
+
+    let body = CONTEXT
+        .run(request())
+        .await
+        .expect_status(StatusCode::OK)
+        .await;
+
+    assert_body_matches! {
+        body,
+        KeyValueResponse { key: "deleted", value: 7, .. }
+    }
+
+    let body: String = CONTEXT
+        .run(request())
+        .await
+        .expect_status(StatusCode::NOT_FOUND)
+        .await;
+
+    assert_eq!(body, "The key did not exist when the delete was applied");
+}
+
+#[tokio::test]
+async fn test_delete_unknown_key() {
+    let request = Request::delete(path!["key-value/never_created"])
+        .with_header("ContentType", "application/json")
+        .with_body("");
+
+    CONTEXT
+        .run(request)
+        .await
+        .expect_status::<String>(StatusCode::NOT_FOUND)
+        .await;
+}
+
+#[tokio::test]
+async fn test_node_stats() {
+    let request = Request::get(path!["nodes/1/stats"])
+        .with_header("ContentType", "application/json")
+        .with_body("");
+
+    let body = CONTEXT
+        .run(request)
+        .await
+        .expect_status(StatusCode::OK)
+        .await;
+
+    assert_body_matches! {
+        body,
+        NodeStatsResponse { pid: 1,..}
+    }
+}
+
+#[tokio::test]
+async fn test_configuration_before_membership_change() {
+    let request = Request::get(path!["admin/configuration"])
+        .with_header("ContentType", "application/json")
+        .with_body("");
+
+    CONTEXT
+        .run(request)
+        .await
+        .expect_status::<String>(StatusCode::NOT_FOUND)
+        .await;
+}
+
+#[tokio::test]
+async fn test_get_snapshot() {
+    let request = Request::post(path!["key-value"])
+        .with_header("ContentType", "application/json")
+        .with_body(KeyValue {
+            key: String::from("snapshotted"),
+            value: 11,
+        });
+
+    let created: KeyValueResponse = CONTEXT
+        .run(request)
+        .await
+        .expect_status(StatusCode::CREATED)
+        .await;
+
+    let request = Request::get(path!["snapshot"])
+        .with_header("ContentType", "application/json")
+        .with_body("");
+
+    let exported: SnapshotResponse = CONTEXT
+        .run(request)
+        .await
+        .expect_status(StatusCode::OK)
+        .await;
+
+    assert!(exported.decided_idx >= created.decided_idx);
+    assert_eq!(exported.snapshot.snapshotted.get("snapshotted"), Some(&11));
+}
+
+#[tokio::test]
+async fn test_post_snapshot_into_cluster_with_keys() {
+    let request = Request::post(path!["key-value"])
+        .with_header("ContentType", "application/json")
+        .with_body(KeyValue {
+            key: String::from("before_restore"),
+            value: 1,
+        });
+
+    CONTEXT
+        .run(request)
+        .await
+        .expect_status::<KeyValueResponse>(StatusCode::CREATED)
+        .await;
+
+    let request = Request::post(path!["snapshot"])
+        .with_header("ContentType", "application/json")
+        .with_body(serde_json::json!({
+            "decided_idx": 1,
+            "snapshot": { "snapshotted": { "restored": 1 }, "deleted": [], "sessions": {}, "pending": [] }
+        }));
+
+    CONTEXT
+        .run(request)
+        .await
+        .expect_status::<String>(StatusCode::CONFLICT)
+        .await;
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct KeyValue {
     pub key: String,
@@ -218,3 +495,31 @@ pub struct KeyValueCas {
     pub old_value: u64,
     pub new_value: u64,
 }
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct CasFailedResponse {
+    pub key: String,
+    pub current_value: Option<u64>,
+    pub decided_idx: u64,
+    pub error: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct NodeStatsResponse {
+    pid: u64,
+    requests: u64,
+    decided_idx: u64,
+    applied_idx: u64,
+    leader: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct SnapshotResponse {
+    pub decided_idx: u64,
+    pub snapshot: Snapshot,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Snapshot {
+    pub snapshotted: HashMap<String, u64>,
+}